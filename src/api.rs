@@ -1,50 +1,214 @@
-use serde::Deserialize;
-use std::error::Error;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, error::Error, fmt, future::Future, sync::Arc};
+use strum::EnumMessage;
 use url::Url;
 
-use crate::config::TimeRange;
+use crate::config::{Config, TimeRange};
+
+// The most items Spotify returns per request, on every endpoint used here
+const PAGE_SIZE: u32 = 50;
 
 pub struct Api {
     access_token: String,
     time_range: TimeRange,
+    deduplicate_albums: bool,
+    deduplicate_genres: bool,
+    client: Arc<reqwest::Client>,
 }
 
 impl Api {
-    pub fn new(access_token: String, time_range: TimeRange) -> Self {
+    pub fn new(access_token: String, config: &Config, client: Arc<reqwest::Client>) -> Self {
         Api {
             access_token,
-            time_range,
+            time_range: config.time_range,
+            deduplicate_albums: config.deduplicate_albums,
+            deduplicate_genres: config.deduplicate_genres,
+            client,
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn fetch_user_top_artists(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<Artist>, Box<dyn Error>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut top_artists: Vec<Artist> = self.fetch_top_items("artists", limit, offset).await?;
+        // Other object types, like user profiles, occasionally show up among the artists
+        top_artists.retain(|artist| artist.type_ == "artist");
+        if offset == 0 && top_artists.is_empty() {
+            return Err(ApiError::NoListeningData(self.time_range).into());
+        }
+        if self.deduplicate_genres {
+            // Keep only the highest ranked artist for each primary genre. Artists without any
+            // genres have nothing to compare, so they are all kept.
+            let mut seen_genres = HashSet::new();
+            top_artists.retain(|artist| match artist.genres.first() {
+                Some(genre) => seen_genres.insert(genre.clone()),
+                None => true,
+            });
         }
+        Ok(top_artists)
     }
 
-    pub async fn fetch_user_top_artists(&self, limit: u32) -> Result<Vec<Artist>, Box<dyn Error>> {
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn fetch_user_top_tracks(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<Track>, Box<dyn Error>> {
         if limit == 0 {
             return Ok(Vec::new());
         }
 
-        let url = self.build_url("artists", limit);
+        let mut top_tracks: Vec<Track> = self.fetch_top_items("tracks", limit, offset).await?;
+        if offset == 0 && top_tracks.is_empty() {
+            return Err(ApiError::NoListeningData(self.time_range).into());
+        }
+        if self.deduplicate_albums {
+            // Keep only the highest ranked track from each album. Local files have no album id,
+            // so they are all kept.
+            let mut seen_albums = HashSet::new();
+            top_tracks.retain(|track| match &track.album.id {
+                Some(id) => seen_albums.insert(id.clone()),
+                None => true,
+            });
+        }
+        Ok(top_tracks)
+    }
+
+    async fn fetch_top_items<T: for<'de> Deserialize<'de>>(
+        &self,
+        endpoint: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<T>, Box<dyn Error>> {
+        fetch_pages(limit, offset, |page_size, page_offset| {
+            let url = self.build_url(endpoint, page_size, page_offset);
+            async move {
+                self.fetch_spotify_api::<PaginatedResponse<T>>(&url)
+                    .await
+                    .map(|page| page.items)
+            }
+        })
+        .await
+    }
 
-        let top_artists: TopArtistsResponse = self.fetch_spotify_api(&url).await?;
-        Ok(top_artists.items)
+    // Both requests run concurrently; a limit of 0 skips that request
+    pub async fn fetch_top_items_batch(
+        &self,
+        track_limit: u32,
+        artist_limit: u32,
+    ) -> Result<(Vec<Track>, Vec<Artist>), Box<dyn Error>> {
+        join_top_items(
+            self.fetch_user_top_tracks(track_limit, 0),
+            self.fetch_user_top_artists(artist_limit, 0),
+        )
+        .await
     }
 
-    pub async fn fetch_user_top_tracks(&self, limit: u32) -> Result<Vec<Track>, Box<dyn Error>> {
+    // Unlike the top items, this doesn't depend on the time range
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn fetch_recently_played(
+        &self,
+        limit: u32,
+    ) -> Result<Vec<PlayHistoryItem>, Box<dyn Error>> {
         if limit == 0 {
             return Ok(Vec::new());
         }
 
-        let url = self.build_url("tracks", limit);
+        let mut items = Vec::new();
+        let mut before = None;
+        while (items.len() as u32) < limit {
+            let page_size = (limit - items.len() as u32).min(PAGE_SIZE);
+            let mut url =
+                Url::parse("https://api.spotify.com/v1/me/player/recently-played").unwrap();
+            url.query_pairs_mut()
+                .append_pair("limit", &page_size.to_string());
+            if let Some(before) = &before {
+                url.query_pairs_mut().append_pair("before", before);
+            }
 
-        let top_tracks: TopTracksResponse = self.fetch_spotify_api(&url).await?;
-        Ok(top_tracks.items)
+            let page: RecentlyPlayedResponse = self.fetch_spotify_api(url.as_str()).await?;
+            let exhausted = page.items.is_empty();
+            items.extend(page.items);
+            before = page.cursors.and_then(|cursors| cursors.before);
+            if exhausted || before.is_none() {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    // Returns None when nothing is playing, which Spotify answers with an empty response
+    pub async fn fetch_currently_playing(
+        &self,
+    ) -> Result<Option<CurrentlyPlayingContext>, Box<dyn Error>> {
+        let mut url = Url::parse("https://api.spotify.com/v1/me/player/currently-playing").unwrap();
+        url.query_pairs_mut()
+            .append_pair("additional_types", CURRENTLY_PLAYING_TYPES);
+
+        let response = self.send_spotify_request(url.as_str()).await?;
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
     }
 
-    fn build_url(&self, endpoint: &str, limit: u32) -> String {
+    pub async fn fetch_user_profile(&self) -> Result<UserProfile, Box<dyn Error>> {
+        self.fetch_spotify_api("https://api.spotify.com/v1/me")
+            .await
+    }
+
+    pub async fn fetch_recommendations(
+        &self,
+        seed_tracks: &[&str],
+        seed_artists: &[&str],
+    ) -> Result<Vec<Track>, Box<dyn Error>> {
+        let mut url = Url::parse("https://api.spotify.com/v1/recommendations").unwrap();
+        url.query_pairs_mut()
+            .append_pair("seed_tracks", &seed_tracks.join(","))
+            .append_pair("seed_artists", &seed_artists.join(","));
+
+        let recommendations: RecommendationsResponse = self.fetch_spotify_api(url.as_str()).await?;
+        Ok(recommendations.tracks)
+    }
+
+    pub async fn fetch_new_releases(&self, limit: u32) -> Result<Vec<Album>, Box<dyn Error>> {
+        let mut url = Url::parse("https://api.spotify.com/v1/browse/new-releases").unwrap();
+        url.query_pairs_mut()
+            .append_pair("limit", &limit.min(PAGE_SIZE).to_string());
+
+        let new_releases: NewReleasesResponse = self.fetch_spotify_api(url.as_str()).await?;
+        Ok(new_releases.albums.items)
+    }
+
+    pub async fn search(
+        &self,
+        query: &str,
+        item_type: &str,
+        limit: u32,
+    ) -> Result<SearchResponse, Box<dyn Error>> {
+        let mut url = Url::parse("https://api.spotify.com/v1/search").unwrap();
+        url.query_pairs_mut()
+            .append_pair("q", query)
+            .append_pair("type", item_type)
+            .append_pair("limit", &limit.min(PAGE_SIZE).to_string());
+
+        self.fetch_spotify_api(url.as_str()).await
+    }
+
+    fn build_url(&self, endpoint: &str, limit: u32, offset: u32) -> String {
         let base = format!("https://api.spotify.com/v1/me/top/{}", endpoint);
         let mut url = Url::parse(&base).unwrap();
         url.query_pairs_mut()
             .append_pair("time_range", &self.time_range.to_string())
-            .append_pair("limit", &limit.to_string());
+            .append_pair("limit", &limit.to_string())
+            .append_pair("offset", &offset.to_string());
         url.to_string()
     }
 
@@ -52,56 +216,258 @@ impl Api {
         &self,
         url: &str,
     ) -> Result<T, Box<dyn Error>> {
-        let client = reqwest::Client::new();
-        let response = client
+        let response = self.send_spotify_request(url).await?;
+        let body = response.text().await?;
+        serde_json::from_str(&body).map_err(|err| -> Box<dyn Error> {
+            // Spotify sometimes answers server errors with a successful status and a different body
+            if err.to_string().starts_with("missing field `items`") {
+                tracing::debug!("Unexpected response body: {}", body);
+                ApiError::UnexpectedResponse(body).into()
+            } else {
+                err.into()
+            }
+        })
+    }
+
+    #[tracing::instrument(level = "debug", skip(self), fields(url = %url))]
+    async fn send_spotify_request(&self, url: &str) -> Result<reqwest::Response, Box<dyn Error>> {
+        let response = self
+            .client
             .get(url)
             .header("Authorization", format!("Bearer {}", &self.access_token))
             .send()
             .await?;
+        // Spotify being down is not something the user can fix, so keep the message simple
+        if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            return Err(ApiError::ServiceUnavailable.into());
+        }
         if !response.status().is_success() {
             let status = response.status();
+            let request_id = response
+                .headers()
+                .get("x-request-id")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
             let error_text = response.text().await?;
-            return Err(format!("API error {}: {}", status, error_text).into());
+            return Err(match request_id {
+                Some(request_id) => format!(
+                    "API error {}: {}, Request-Id: {}",
+                    status, error_text, request_id
+                ),
+                None => format!("API error {}: {}", status, error_text),
+            }
+            .into());
         }
 
-        Ok(response.json().await?)
+        Ok(response)
+    }
+}
+
+// Waits for both fetches at once, failing as soon as either does
+async fn join_top_items<T, A>(
+    fetch_tracks: impl Future<Output = Result<Vec<T>, Box<dyn Error>>>,
+    fetch_artists: impl Future<Output = Result<Vec<A>, Box<dyn Error>>>,
+) -> Result<(Vec<T>, Vec<A>), Box<dyn Error>> {
+    tokio::try_join!(fetch_tracks, fetch_artists)
+}
+
+// Larger limits than a single page allows take several requests. `fetch_page` is called with
+// the page size and offset of each request and returns that page's items.
+async fn fetch_pages<T, F, Fut>(
+    limit: u32,
+    offset: u32,
+    mut fetch_page: F,
+) -> Result<Vec<T>, Box<dyn Error>>
+where
+    F: FnMut(u32, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<T>, Box<dyn Error>>>,
+{
+    let mut items = Vec::new();
+    while (items.len() as u32) < limit {
+        let page_size = (limit - items.len() as u32).min(PAGE_SIZE);
+        let page = fetch_page(page_size, offset + items.len() as u32).await?;
+        let exhausted = (page.len() as u32) < page_size;
+        items.extend(page);
+        if exhausted {
+            break;
+        }
+    }
+    items.truncate(limit as usize);
+    Ok(items)
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    NoListeningData(TimeRange),
+    ServiceUnavailable,
+    UnexpectedResponse(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::NoListeningData(time_range) => {
+                write!(
+                    f,
+                    "No listening data for the last {}.",
+                    time_range.get_message().unwrap()
+                )?;
+                match time_range {
+                    TimeRange::Short => write!(f, " Try `--time-range medium`."),
+                    TimeRange::Medium => write!(f, " Try `--time-range long`."),
+                    TimeRange::Long => Ok(()),
+                }
+            }
+            ApiError::ServiceUnavailable => write!(
+                f,
+                "Spotify API is temporarily unavailable. Please try again in a few minutes."
+            ),
+            ApiError::UnexpectedResponse(body) => {
+                const MAX_BODY_CHARS: usize = 200;
+                write!(f, "Unexpected response from the Spotify API: ")?;
+                if body.chars().count() > MAX_BODY_CHARS {
+                    let truncated: String = body.chars().take(MAX_BODY_CHARS).collect();
+                    write!(
+                        f,
+                        "{}... (run with --verbose for the full response)",
+                        truncated
+                    )
+                } else {
+                    write!(f, "{}", body)
+                }
+            }
+        }
     }
 }
 
-#[derive(Clone, Deserialize, Debug)]
+impl Error for ApiError {}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Image {
     pub url: String,
     pub height: u32,
     pub width: u32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Artist {
+    pub id: String,
     pub name: String,
     pub images: Vec<Image>,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    #[serde(rename = "type", default = "default_type")]
+    pub type_: String,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct TopArtistsResponse {
-    pub items: Vec<Artist>,
+impl Artist {
+    // Spotify lists an artist's genres roughly from most to least relevant
+    pub fn top_genres(&self, max_genres: usize) -> &[String] {
+        &self.genres[..self.genres.len().min(max_genres)]
+    }
+
+    pub fn format_artist_display(&self, max_genres: usize) -> String {
+        match self.top_genres(max_genres) {
+            [] => self.name.clone(),
+            genres => format!("{} ({})", self.name, genres.join(", ")),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
+fn default_type() -> String {
+    "artist".to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct SimpleArtist {
+    // Null for artists of local files
+    pub id: Option<String>,
     pub name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Album {
+    // Null for albums of local files
+    pub id: Option<String>,
     pub name: String,
+    pub album_type: Option<String>,
     pub images: Vec<Image>,
+    #[serde(default)]
+    pub artists: Vec<SimpleArtist>,
+    #[serde(default)]
+    pub total_tracks: u32,
+    pub release_date: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+impl Album {
+    pub fn type_label(&self) -> Option<&'static str> {
+        match self.album_type.as_deref()? {
+            "single" => Some("Single"),
+            "compilation" => Some("Compilation"),
+            _ => None,
+        }
+    }
+
+    // Release dates may be just a year, or a year and month, depending on the precision Spotify has
+    pub fn release_year(&self) -> Option<&str> {
+        self.release_date.as_deref()?.get(..4)
+    }
+
+    pub fn artist_names(&self) -> String {
+        self.artists
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct ExternalUrls {
+    // Missing for local files
+    #[serde(default)]
+    pub spotify: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Track {
+    // Null for local files, which only exist in the user's own library
+    pub id: Option<String>,
+    pub uri: String,
     pub name: String,
     pub artists: Vec<SimpleArtist>,
     pub album: Album,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    pub preview_url: Option<String>,
+    #[serde(default)]
+    pub available_markets: Option<Vec<String>>,
+    pub linked_from: Option<LinkedTrack>,
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
+// The track Spotify originally returned, before relinking it to one playable in the user's market
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LinkedTrack {
+    pub id: String,
+    pub uri: String,
+}
+
+/// The albums of `tracks`, each listed once at the position of its highest ranked track.
+/// Local files have no album id to compare, so they are all kept.
+pub fn distinct_albums(tracks: &[Track]) -> Vec<&Album> {
+    let mut seen_albums = HashSet::new();
+    tracks
+        .iter()
+        .map(|track| &track.album)
+        .filter(|album| match &album.id {
+            Some(id) => seen_albums.insert(id.as_str()),
+            None => true,
+        })
+        .collect()
 }
 
 impl Track {
@@ -109,17 +475,266 @@ impl Track {
         format!(
             "{} - {} ({})",
             self.name,
-            self.artists
-                .iter()
-                .map(|a| a.name.as_str())
-                .collect::<Vec<_>>()
-                .join(", "),
+            self.artist_names(),
             self.album.name
         )
     }
+
+    pub fn artist_names(&self) -> String {
+        self.artists
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    // Follow-up requests should refer to the track the user actually listened to
+    pub fn original_id(&self) -> Option<&str> {
+        self.linked_from
+            .as_ref()
+            .map_or(self.id.as_deref(), |linked| Some(linked.id.as_str()))
+    }
+
+    // Tracks without market information are assumed to be playable everywhere
+    pub fn is_available_in(&self, country: &str) -> bool {
+        self.available_markets
+            .as_ref()
+            .is_none_or(|markets| markets.iter().any(|market| market == country))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PlayHistoryItem {
+    pub track: Track,
+    // An ISO 8601 timestamp in UTC
+    pub played_at: String,
 }
 
 #[derive(Deserialize, Debug)]
-pub struct TopTracksResponse {
-    items: Vec<Track>,
+pub struct UserProfile {
+    // Only returned when the token has the user-read-private scope
+    pub country: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RecommendationsResponse {
+    tracks: Vec<Track>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PaginatedResponse<T> {
+    pub items: Vec<T>,
+}
+
+// The recently played endpoint pages by timestamp instead of by offset
+#[derive(Deserialize, Debug)]
+pub struct Cursors {
+    pub before: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RecentlyPlayedResponse {
+    items: Vec<PlayHistoryItem>,
+    pub cursors: Option<Cursors>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewReleasesResponse {
+    albums: PaginatedResponse<Album>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchResponse {
+    pub tracks: Option<PaginatedResponse<Track>>,
+    pub artists: Option<PaginatedResponse<Artist>>,
+}
+
+// Passed as additional_types to the currently-playing endpoint, so podcast episodes are returned
+// alongside tracks
+pub const CURRENTLY_PLAYING_TYPES: &str = "track,episode";
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Show {
+    pub name: String,
+}
+
+// A podcast episode, which takes the place of a track when one is playing
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Episode {
+    pub name: String,
+    pub show: Show,
+    pub images: Vec<Image>,
+    #[serde(default)]
+    pub duration_ms: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlayingItem {
+    Track(Track),
+    Episode(Episode),
+}
+
+impl PlayingItem {
+    pub fn duration_ms(&self) -> u64 {
+        match self {
+            PlayingItem::Track(track) => track.duration_ms,
+            PlayingItem::Episode(episode) => episode.duration_ms,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CurrentlyPlayingContext {
+    // Missing during ads and for some local files
+    pub item: Option<PlayingItem>,
+    pub is_playing: bool,
+    #[serde(default)]
+    pub progress_ms: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Serves `available` numbered items and records the (page size, offset) of every request
+    async fn fetch_from(available: u32, limit: u32, offset: u32) -> (Vec<u32>, Vec<(u32, u32)>) {
+        let mut requests = Vec::new();
+        let items = fetch_pages(limit, offset, |page_size, page_offset| {
+            requests.push((page_size, page_offset));
+            let end = (page_offset + page_size).min(available);
+            async move { Ok((page_offset.min(end)..end).collect()) }
+        })
+        .await
+        .unwrap();
+        (items, requests)
+    }
+
+    fn track_on_album(album_id: Option<&str>, album_name: &str) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "id": null,
+            "uri": "",
+            "name": "Song",
+            "artists": [],
+            "album": { "id": album_id, "name": album_name, "images": [] },
+            "preview_url": null,
+            "linked_from": null
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn distinct_albums_keeps_first_occurrence_and_local_files() {
+        let tracks = [
+            track_on_album(Some("a"), "A"),
+            track_on_album(Some("b"), "B"),
+            track_on_album(Some("a"), "A"),
+            track_on_album(None, "Local"),
+            track_on_album(None, "Local"),
+        ];
+        let names: Vec<&str> = distinct_albums(&tracks)
+            .iter()
+            .map(|album| album.name.as_str())
+            .collect();
+        assert_eq!(names, ["A", "B", "Local", "Local"]);
+    }
+
+    // Trimmed from a top tracks response that included a local file
+    #[test]
+    fn local_file_track_deserializes() {
+        let json = r#"{
+            "id": null,
+            "uri": "spotify:local:Artist:Album:Song:215",
+            "name": "Song",
+            "is_local": true,
+            "artists": [{ "id": null, "name": "Artist", "type": "artist", "uri": null }],
+            "album": {
+                "id": null,
+                "name": "Album",
+                "album_type": null,
+                "images": [],
+                "artists": [],
+                "release_date": null
+            },
+            "external_urls": {},
+            "preview_url": null,
+            "duration_ms": 215000
+        }"#;
+        let track: Track = serde_json::from_str(json).unwrap();
+        assert_eq!(track.id, None);
+        assert_eq!(track.original_id(), None);
+        assert_eq!(track.artists[0].id, None);
+        assert_eq!(track.album.id, None);
+        assert_eq!(track.album.type_label(), None);
+    }
+
+    // Each fetch waits for the other to have started, so this only finishes if they run at once
+    #[tokio::test]
+    async fn join_top_items_fetches_both_concurrently() {
+        let (tracks_started, wait_for_tracks) = tokio::sync::oneshot::channel();
+        let (artists_started, wait_for_artists) = tokio::sync::oneshot::channel();
+        let fetch_tracks = async move {
+            tracks_started.send(()).unwrap();
+            wait_for_artists.await.unwrap();
+            Ok(vec!["track 1", "track 2"])
+        };
+        let fetch_artists = async move {
+            artists_started.send(()).unwrap();
+            wait_for_tracks.await.unwrap();
+            Ok(vec!["artist 1"])
+        };
+
+        let joined = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            join_top_items(fetch_tracks, fetch_artists),
+        )
+        .await
+        .expect("fetches ran one after the other")
+        .unwrap();
+        assert_eq!(joined, (vec!["track 1", "track 2"], vec!["artist 1"]));
+    }
+
+    #[tokio::test]
+    async fn join_top_items_fails_if_either_fetch_fails() {
+        let result = join_top_items(async { Ok(vec![1]) }, async {
+            Err::<Vec<u32>, _>("API error 500".into())
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "API error 500");
+    }
+
+    #[tokio::test]
+    async fn fetch_pages_splits_limit_into_pages() {
+        let (items, requests) = fetch_from(100, 60, 0).await;
+        assert_eq!(items, (0..60).collect::<Vec<_>>());
+        assert_eq!(requests, vec![(50, 0), (10, 50)]);
+    }
+
+    #[tokio::test]
+    async fn fetch_pages_stops_after_short_page() {
+        let (items, requests) = fetch_from(55, 120, 0).await;
+        assert_eq!(items.len(), 55);
+        assert_eq!(requests, vec![(50, 0), (50, 50)]);
+    }
+
+    #[tokio::test]
+    async fn fetch_pages_stops_on_empty_page() {
+        let (items, requests) = fetch_from(50, 60, 0).await;
+        assert_eq!(items.len(), 50);
+        assert_eq!(requests, vec![(50, 0), (10, 50)]);
+    }
+
+    #[tokio::test]
+    async fn fetch_pages_continues_from_offset() {
+        let (items, requests) = fetch_from(100, 60, 20).await;
+        assert_eq!(items, (20..80).collect::<Vec<_>>());
+        assert_eq!(requests, vec![(50, 20), (10, 70)]);
+    }
+
+    #[tokio::test]
+    async fn fetch_pages_with_zero_limit_makes_no_requests() {
+        let (items, requests) = fetch_from(100, 0, 0).await;
+        assert!(items.is_empty());
+        assert!(requests.is_empty());
+    }
 }
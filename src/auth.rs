@@ -4,86 +4,224 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
     error::Error,
-    fs,
-    path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use url::Url;
 
+use crate::cli::Command;
+use crate::config::{Config, ItemType};
+
 const CLIENT_ID: &str = "ebdbdb22841c48648acf563e594d928e";
 const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
 const AUTHORIZE_URL: &str = "https://accounts.spotify.com/authorize";
-const REDIRECT_URI: &str = "http://localhost:8888/callback";
+const CALLBACK_PATH: &str = "/callback";
 const LOCALHOST: &str = "127.0.0.1";
-const PORT: u16 = 8888;
 const AUTH_SCOPE: [&str; 1] = ["user-top-read"];
+const TOKEN_LIFETIME_SECS: u64 = 3600;
+const CODE_VERIFIER_BYTES: usize = 32;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AuthToken {
     access_token: String,
     refresh_token: String,
     expires_at: u64,
+    #[serde(default)]
+    obtained_at: u64,
+    #[serde(default)]
+    scope: Option<String>,
 }
 
 impl AuthToken {
-    pub async fn get_valid_token() -> Result<String, Box<dyn Error>> {
-        match Self::load() {
-            Ok(mut token_data) => {
+    pub async fn get_valid_token(
+        config: &Config,
+        command: Option<&Command>,
+        client: &reqwest::Client,
+    ) -> Result<String, Box<dyn Error>> {
+        // Only the token is kept, so the future stays Send and can run on a spawned task
+        let stored = Self::load().ok();
+        let required = required_scopes(config, command);
+        // A token granted before a feature was enabled cannot be refreshed into more scopes
+        let missing_scopes = stored
+            .as_ref()
+            .is_some_and(|token_data| !token_data.has_scopes(&required));
+        // Scopes granted before are asked for again, so switching between commands doesn't
+        // re-authorize every time
+        let mut scopes: Vec<String> = required.iter().map(ToString::to_string).collect();
+        for granted in stored
+            .as_ref()
+            .and_then(|token_data| token_data.scope())
+            .unwrap_or_default()
+            .split_whitespace()
+        {
+            if !scopes.iter().any(|scope| scope == granted) {
+                scopes.push(granted.to_string());
+            }
+        }
+        match stored.filter(|_| !missing_scopes) {
+            Some(mut token_data) => {
                 if token_data.is_expired() {
-                    println!("Access token expired, refreshing...");
-                    token_data = Self::refresh_access_token(&token_data.refresh_token).await?;
+                    if !config.quiet {
+                        tracing::info!("Access token expired, refreshing...");
+                    }
+                    token_data = Self::refresh_access_token(&token_data, client).await?;
                     token_data.save()?;
-                    println!("Token refreshed successfully!");
+                    if !config.quiet {
+                        tracing::info!("Token refreshed successfully!");
+                    }
                 }
                 Ok(token_data.access_token)
             }
-            Err(_) => {
-                println!("No tokens found, starting authorization flow...");
-                let auth = Auth::new();
-                let token_data = auth.perform_oauth().await?;
+            None => {
+                if !config.quiet {
+                    if missing_scopes {
+                        tracing::info!(
+                            "Stored token is missing required scopes, starting authorization flow..."
+                        );
+                    } else {
+                        tracing::info!("No tokens found, starting authorization flow...");
+                    }
+                }
+                let auth = Auth::new(config, scopes);
+                let token_data = auth.perform_oauth(client).await?;
                 token_data.save()?;
                 Ok(token_data.access_token)
             }
         }
     }
 
+    // Tokens saved without a scope predate scope tracking, when only the base scopes were asked for
+    fn has_scopes(&self, required: &[&str]) -> bool {
+        let granted: Vec<&str> = match self.scope.as_deref() {
+            Some(scope) => scope.split_whitespace().collect(),
+            None => AUTH_SCOPE.to_vec(),
+        };
+        required.iter().all(|required| granted.contains(required))
+    }
+
     fn is_expired(&self) -> bool {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
-        now >= self.expires_at - 60
+        now >= self.expires_at.saturating_sub(60)
+    }
+
+    // Tokens saved before obtained_at was recorded have no known age
+    pub fn token_age(&self) -> Option<Duration> {
+        if self.obtained_at == 0 {
+            return None;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        Some(Duration::from_secs(now.saturating_sub(self.obtained_at)))
+    }
+
+    pub fn scope(&self) -> Option<&str> {
+        self.scope.as_deref()
     }
 
     fn save(&self) -> Result<(), Box<dyn Error>> {
-        let path = Self::get_token_path();
-        let toml = toml::to_string_pretty(self)?;
-        fs::write(path, toml)?;
+        self.save_to(&Self::get_token_path()?)
+    }
+
+    fn save_to(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string_pretty(self)?;
+        // The refresh token is as good as a password, so the file is never readable by others,
+        // not even between being created and written. On Windows the file is left with the
+        // permissions of the user's data directory, which other users can't read by default.
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        // The mode only applies to new files, so tighten one written by an older version too
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(json.as_bytes())?;
         Ok(())
     }
 
-    fn load() -> Result<Self, Box<dyn Error>> {
-        let path = Self::get_token_path();
-        let toml_str = fs::read_to_string(path)?;
-        let token_data = toml::from_str(&toml_str)?;
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        Self::migrate_legacy_token()?;
+        let path = Self::get_token_path()?;
+        let json_str = fs::read_to_string(path)?;
+        let mut token_data: Self = serde_json::from_str(&json_str)?;
+
+        // A token can never legitimately expire this far out, so force a refresh
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if token_data.expires_at > now + 2 * TOKEN_LIFETIME_SECS {
+            tracing::warn!("Stored token has an invalid expiry time, it will be refreshed");
+            token_data.expires_at = 0;
+        }
         Ok(token_data)
     }
 
-    fn get_token_path() -> PathBuf {
-        let mut path = dirs::config_dir().expect("Could not find config directory");
+    fn get_token_path() -> Result<PathBuf, Box<dyn Error>> {
+        Ok(Self::get_token_path_for(
+            dirs::data_dir().ok_or("Could not find data directory")?,
+        ))
+    }
+
+    fn get_token_path_for(base: PathBuf) -> PathBuf {
+        let mut path = base;
         path.push("spotifyfetch");
         fs::create_dir_all(&path).ok();
-        path.push("tokens.toml");
+        path.push("tokens.json");
         path
     }
 
-    async fn refresh_access_token(refresh_token: &str) -> Result<Self, Box<dyn Error>> {
-        let client = reqwest::Client::new();
+    fn get_legacy_token_path() -> Option<PathBuf> {
+        let mut path = dirs::config_dir()?;
+        path.push("spotifyfetch");
+        path.push("tokens.toml");
+        Some(path)
+    }
 
+    // Tokens used to live next to the config file; move them to the data directory
+    fn migrate_legacy_token() -> Result<(), Box<dyn Error>> {
+        let Some(legacy_path) = Self::get_legacy_token_path() else {
+            return Ok(());
+        };
+        let path = Self::get_token_path()?;
+        if path.exists() || !legacy_path.exists() {
+            return Ok(());
+        }
+
+        let toml_str = fs::read_to_string(&legacy_path)?;
+        let token_data: Self = toml::from_str(&toml_str)?;
+        token_data.save()?;
+        fs::remove_file(&legacy_path)?;
+        tracing::info!(
+            "Migrated tokens from {} to {}",
+            legacy_path.display(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    async fn refresh_access_token(
+        token_data: &Self,
+        client: &reqwest::Client,
+    ) -> Result<Self, Box<dyn Error>> {
         let params = [
             ("grant_type", "refresh_token"),
-            ("refresh_token", refresh_token),
+            ("refresh_token", token_data.refresh_token.as_str()),
             ("client_id", CLIENT_ID),
         ];
 
@@ -94,29 +232,55 @@ impl AuthToken {
             access_token: String,
             refresh_token: Option<String>, // Sometimes Spotify returns a new one
             expires_in: u64,
+            scope: Option<String>,
         }
 
         let refresh_response: RefreshResponse = response.json().await?;
 
-        let expires_at = SystemTime::now()
+        let obtained_at = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_secs()
-            + refresh_response.expires_in;
+            .as_secs();
+        let expires_at = obtained_at + refresh_response.expires_in;
 
         Ok(AuthToken {
             access_token: refresh_response.access_token,
             refresh_token: refresh_response
                 .refresh_token
-                .unwrap_or_else(|| refresh_token.to_string()),
+                .unwrap_or_else(|| token_data.refresh_token.clone()),
             expires_at,
+            obtained_at,
+            scope: refresh_response.scope.or_else(|| token_data.scope.clone()),
         })
     }
 }
 
+#[derive(Debug)]
+pub enum AuthError {
+    PortBound(u16),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::PortBound(port) => write!(
+                f,
+                "Port {} is in use. Free it, or set `auth_port` in the config to another port \
+                 and register http://localhost:<port>/callback as a redirect URI of the app",
+                port
+            ),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
 struct Auth {
     code_verifier: String,
-    auth_url: String,
+    code_challenge: String,
+    scopes: Vec<String>,
+    port: u16,
+    quiet: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -124,70 +288,132 @@ struct TokenResponse {
     access_token: String,
     refresh_token: String,
     expires_in: u64,
+    scope: Option<String>,
 }
 
 impl Auth {
-    fn new() -> Self {
-        let code_verifier = Self::generate_code_verifier();
+    fn new(config: &Config, scopes: Vec<String>) -> Self {
+        let code_verifier = Self::generate_code_verifier(CODE_VERIFIER_BYTES);
         let code_challenge = Self::generate_code_challenge(&code_verifier);
-        let auth_url = Self::build_auth_url(&code_challenge);
 
         Auth {
             code_verifier,
-            auth_url,
+            code_challenge,
+            scopes,
+            port: config.auth_port,
+            quiet: config.quiet,
         }
     }
 
-    async fn perform_oauth(&self) -> Result<AuthToken, Box<dyn Error>> {
-        println!("Opening browser for authorization...");
-        open::that(self.auth_url.clone())?;
+    async fn perform_oauth(&self, client: &reqwest::Client) -> Result<AuthToken, Box<dyn Error>> {
+        let server = Self::bind_callback_server(self.port)?;
+        let redirect_uri = Self::redirect_uri(self.port);
+        let auth_url = Self::build_auth_url(&self.code_challenge, &self.scopes, &redirect_uri);
+
+        if !self.quiet {
+            tracing::info!("Opening browser for authorization...");
+        }
+        // open can succeed without a browser actually appearing, e.g. inside tmux or over SSH.
+        // The URL is needed to log in at all, so it is printed even with --quiet.
+        let opened = open::that(&auth_url);
+        eprintln!("If the browser didn't open, visit: {}", auth_url);
+        if let Err(err) = opened {
+            tracing::warn!("Failed to open browser: {}", err);
+        }
 
-        let code = Self::wait_for_callback()?;
+        let code = Self::wait_for_callback(server, self.quiet)?;
 
-        let token_response = self.exchange_code_for_token(&code).await?;
+        let token_response = self
+            .exchange_code_for_token(&code, &redirect_uri, client)
+            .await?;
 
-        let expires_at = SystemTime::now()
+        let obtained_at = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_secs()
-            + token_response.expires_in;
+            .as_secs();
+        let expires_at = obtained_at + token_response.expires_in;
 
         Ok(AuthToken {
             access_token: token_response.access_token,
             refresh_token: token_response.refresh_token,
             expires_at,
+            obtained_at,
+            scope: token_response.scope,
         })
     }
 
-    fn wait_for_callback() -> Result<String, Box<dyn Error>> {
-        let server = tiny_http::Server::http(format!("{}:{}", LOCALHOST, PORT)).unwrap();
-        println!("Waiting for authorization callback...");
+    // Spotify only accepts a redirect URI registered for the app exactly, so there is no point
+    // in falling back to another port
+    fn bind_callback_server(port: u16) -> Result<tiny_http::Server, AuthError> {
+        tiny_http::Server::http(format!("{}:{}", LOCALHOST, port)).map_err(|err| {
+            tracing::debug!("Could not listen on port {}: {}", port, err);
+            AuthError::PortBound(port)
+        })
+    }
 
-        let request = server.recv()?;
-        let url = format!("http://{}{}", LOCALHOST, request.url());
-        let parsed_url = Url::parse(&url)?;
+    fn redirect_uri(port: u16) -> String {
+        format!("http://localhost:{}{}", port, CALLBACK_PATH)
+    }
 
-        let code = parsed_url
-            .query_pairs()
-            .find(|(key, _)| key == "code")
-            .map(|(_, value)| value.to_string())
-            .ok_or("No code found in callback")?;
+    fn wait_for_callback(server: tiny_http::Server, quiet: bool) -> Result<String, Box<dyn Error>> {
+        if !quiet {
+            tracing::info!("Waiting for authorization callback...");
+        }
 
-        let response = tiny_http::Response::from_string(
-            "Authorization successful! You can close this window.",
-        );
-        request.respond(response)?;
+        while let Ok(request) = server.recv() {
+            // Browsers may also request paths like /favicon.ico around the redirect
+            if !request.url().starts_with(CALLBACK_PATH) {
+                Self::respond_not_found(request)?;
+                continue;
+            }
+
+            let url = format!("http://{}{}", LOCALHOST, request.url());
+            let parsed_url = Url::parse(&url)?;
+            if parsed_url.path() != CALLBACK_PATH {
+                Self::respond_not_found(request)?;
+                continue;
+            }
+
+            for (key, _) in parsed_url.query_pairs() {
+                if !matches!(key.as_ref(), "code" | "state" | "error") {
+                    tracing::warn!("Unexpected parameter `{}` in authorization callback", key);
+                }
+            }
+
+            let code = parsed_url
+                .query_pairs()
+                .find(|(key, _)| key == "code")
+                .map(|(_, value)| value.to_string())
+                .ok_or("No code found in callback")?;
 
-        Ok(code)
+            let response = tiny_http::Response::from_string(
+                "Authorization successful! You can close this window.",
+            );
+            request.respond(response)?;
+
+            return Ok(code);
+        }
+
+        Err("Authorization server stopped before receiving the callback".into())
     }
 
-    async fn exchange_code_for_token(&self, code: &str) -> Result<TokenResponse, Box<dyn Error>> {
-        let client = reqwest::Client::new();
+    fn respond_not_found(request: tiny_http::Request) -> Result<(), Box<dyn Error>> {
+        let response = tiny_http::Response::from_string("Not found")
+            .with_status_code(tiny_http::StatusCode(404));
+        request.respond(response)?;
+        Ok(())
+    }
 
+    async fn exchange_code_for_token(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        client: &reqwest::Client,
+    ) -> Result<TokenResponse, Box<dyn Error>> {
         let params = [
             ("grant_type", "authorization_code"),
             ("code", code),
-            ("redirect_uri", REDIRECT_URI),
+            ("redirect_uri", redirect_uri),
             ("client_id", CLIENT_ID),
             ("code_verifier", &self.code_verifier),
         ];
@@ -203,9 +429,12 @@ impl Auth {
         Ok(response)
     }
 
-    fn generate_code_verifier() -> String {
-        let random_bytes: Vec<u8> = (0..32).map(|_| rand::rng().random::<u8>()).collect();
-        URL_SAFE_NO_PAD.encode(random_bytes)
+    fn generate_code_verifier(n_bytes: usize) -> String {
+        let random_bytes: Vec<u8> = (0..n_bytes).map(|_| rand::rng().random::<u8>()).collect();
+        let encoded = URL_SAFE_NO_PAD.encode(random_bytes);
+        // RFC 7636 requires the verifier to be between 43 and 128 characters
+        debug_assert!((43..=128).contains(&encoded.len()));
+        encoded
     }
 
     fn generate_code_challenge(verifier: &str) -> String {
@@ -215,15 +444,15 @@ impl Auth {
         URL_SAFE_NO_PAD.encode(result)
     }
 
-    fn build_auth_url(code_challenge: &str) -> String {
+    fn build_auth_url(code_challenge: &str, scopes: &[String], redirect_uri: &str) -> String {
         let mut url = Url::parse(AUTHORIZE_URL).unwrap();
         url.query_pairs_mut()
             .append_pair("client_id", CLIENT_ID)
             .append_pair("response_type", "code")
-            .append_pair("redirect_uri", REDIRECT_URI)
+            .append_pair("redirect_uri", redirect_uri)
             .append_pair("code_challenge_method", "S256")
             .append_pair("code_challenge", code_challenge)
-            .append_pair("scope", &AUTH_SCOPE.join(" "));
+            .append_pair("scope", &scopes.join(" "));
 
         url.to_string()
     }
@@ -231,6 +460,114 @@ impl Auth {
 
 impl Default for Auth {
     fn default() -> Self {
-        Self::new()
+        let config = Config::default();
+        let scopes = required_scopes(&config, None)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        Self::new(&config, scopes)
+    }
+}
+
+// Only ask for the scopes that the enabled features and the command being run actually use
+pub fn required_scopes(config: &Config, command: Option<&Command>) -> Vec<&'static str> {
+    let mut scopes = AUTH_SCOPE.to_vec();
+    if let Some(Command::Now) = command {
+        scopes.push("user-read-currently-playing");
+    }
+    // The user's country is needed to flag tracks that are unavailable in their market
+    if config.list_view == ItemType::Track {
+        scopes.push("user-read-private");
+    }
+    if config.list_view == ItemType::RecentlyPlayed || config.image_view == ItemType::RecentlyPlayed
+    {
+        scopes.push("user-read-recently-played");
+    }
+    scopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_path_is_in_spotifyfetch_dir_under_base() {
+        let base = tempfile::tempdir().unwrap();
+        let path = AuthToken::get_token_path_for(base.path().to_path_buf());
+        assert_eq!(path, base.path().join("spotifyfetch").join("tokens.json"));
+        assert!(path.parent().unwrap().is_dir());
+    }
+
+    #[test]
+    fn code_verifier_has_default_length() {
+        let verifier = Auth::generate_code_verifier(CODE_VERIFIER_BYTES);
+        assert_eq!(verifier.len(), 43);
+    }
+
+    #[test]
+    fn code_verifier_is_url_safe_base64_without_padding() {
+        let verifier = Auth::generate_code_verifier(CODE_VERIFIER_BYTES);
+        assert!(
+            verifier
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        );
+    }
+
+    // The example from RFC 7636, Appendix B
+    #[test]
+    fn code_challenge_matches_rfc_7636_example() {
+        assert_eq!(
+            Auth::generate_code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    fn token_with_scope(scope: Option<&str>) -> AuthToken {
+        AuthToken {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            expires_at: 0,
+            obtained_at: 0,
+            scope: scope.map(String::from),
+        }
+    }
+
+    #[test]
+    fn has_scopes_requires_every_scope() {
+        let token = token_with_scope(Some("user-top-read user-read-private"));
+        assert!(token.has_scopes(&AUTH_SCOPE));
+        assert!(!token.has_scopes(&["user-top-read", "user-read-recently-played"]));
+    }
+
+    #[test]
+    fn token_without_scope_has_base_scopes() {
+        let token = token_with_scope(None);
+        assert!(token.has_scopes(&AUTH_SCOPE));
+        assert!(!token.has_scopes(&["user-top-read", "user-read-private"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saved_token_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        token_with_scope(None).save_to(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn currently_playing_scope_is_only_required_for_now() {
+        let config = Config::default();
+        assert!(!required_scopes(&config, None).contains(&"user-read-currently-playing"));
+        assert!(
+            required_scopes(&config, Some(&Command::Now)).contains(&"user-read-currently-playing")
+        );
     }
 }
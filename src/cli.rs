@@ -0,0 +1,90 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::config::{Format, TimeRange};
+use crate::export::ExportFormat;
+
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Time range to show stats for, overriding the config file
+    #[arg(long, value_enum)]
+    pub time_range: Option<TimeRange>,
+
+    /// Output format, overriding the config file and SPOTIFYFETCH_FORMAT
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+
+    /// Print a QR code linking to your top track below the display
+    #[arg(long)]
+    pub qr: bool,
+
+    /// Play the 30-second preview of your top track
+    #[arg(long)]
+    pub preview: bool,
+
+    /// List tracks recommended from your top track and artist instead of your top items
+    #[arg(long)]
+    pub recommend: bool,
+
+    /// Print debug traces with timings for network calls to stderr
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Only print the stats themselves, and nothing but errors to stderr
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Show the config location and authorization status, then exit
+    #[arg(long)]
+    pub status: bool,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Search Spotify for tracks and artists
+    Search {
+        /// Search query
+        query: String,
+    },
+    /// Export your top tracks and artists to a file
+    Export {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+
+        /// File or directory to write to, defaults to stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Show the newest album releases on Spotify
+    New,
+    /// Show what is playing right now
+    Now,
+    /// Read or change the config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ConfigCommand {
+    /// Set a config field, e.g. `config set image_width 35`
+    Set {
+        /// Name of the config field
+        key: String,
+        /// New value, e.g. 35, true, Track or "#1DB954"
+        value: String,
+    },
+    /// Print the current value of a config field
+    Get {
+        /// Name of the config field
+        key: String,
+    },
+    /// Show every config field with its value and valid range
+    List,
+}
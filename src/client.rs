@@ -0,0 +1,27 @@
+use reqwest::header::{ACCEPT, HeaderMap, HeaderValue};
+use std::time::Duration;
+
+use crate::config::Config;
+
+const USER_AGENT: &str = concat!(
+    "spotifyfetch/",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("CARGO_PKG_REPOSITORY"),
+    ")"
+);
+
+pub fn build_http_client(config: &Config) -> reqwest::Client {
+    let mut headers = HeaderMap::new();
+    // No Content-Type here: the API calls are GETs without a body, and the token requests
+    // set their own through .form()
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+    reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .default_headers(headers)
+        .timeout(Duration::from_secs(config.request_timeout_secs))
+        .gzip(true)
+        .build()
+        .expect("Failed to build HTTP client")
+}
@@ -0,0 +1,85 @@
+use std::env;
+
+use crate::image::Rgb;
+
+pub const ANSI_RESET: &str = "\x1b[0m";
+// Channel values used by the 6x6x6 color cube in the 256-color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+pub fn parse_hex(hex: &str) -> Option<Rgb> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+pub fn hex_to_ansi_truecolor(hex: &str) -> String {
+    parse_hex(hex).map(truecolor_escape).unwrap_or_default()
+}
+
+// Escape sequence for a foreground color, using the 256-color palette without truecolor support
+pub fn hex_to_ansi(hex: &str) -> Option<String> {
+    let rgb = parse_hex(hex)?;
+    if supports_truecolor() {
+        Some(hex_to_ansi_truecolor(hex))
+    } else {
+        Some(ansi256_escape(rgb))
+    }
+}
+
+pub fn rgb_to_ansi(rgb: Rgb) -> String {
+    if supports_truecolor() {
+        truecolor_escape(rgb)
+    } else {
+        ansi256_escape(rgb)
+    }
+}
+
+pub fn supports_truecolor() -> bool {
+    env::var("COLORTERM").is_ok_and(|value| value == "truecolor" || value == "24bit")
+}
+
+fn truecolor_escape((r, g, b): Rgb) -> String {
+    format!("\x1b[38;2;{};{};{}m", r, g, b)
+}
+
+fn ansi256_escape(rgb: Rgb) -> String {
+    format!("\x1b[38;5;{}m", nearest_ansi256(rgb))
+}
+
+pub fn nearest_ansi256(rgb: Rgb) -> u8 {
+    let cube_index = |value: u8| {
+        CUBE_LEVELS
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, level)| level.abs_diff(value))
+            .map(|(i, _)| i as u8)
+            .unwrap_or(0)
+    };
+    let (r, g, b) = (cube_index(rgb.0), cube_index(rgb.1), cube_index(rgb.2));
+    let cube_color = (
+        CUBE_LEVELS[r as usize],
+        CUBE_LEVELS[g as usize],
+        CUBE_LEVELS[b as usize],
+    );
+    let cube = (16 + 36 * r + 6 * g + b, distance(rgb, cube_color));
+
+    // The grayscale ramp runs from 8 to 238 in steps of 10
+    let average = (rgb.0 as u16 + rgb.1 as u16 + rgb.2 as u16) / 3;
+    let gray_step = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray_level = 8 + gray_step * 10;
+    let gray = (
+        232 + gray_step,
+        distance(rgb, (gray_level, gray_level, gray_level)),
+    );
+
+    if gray.1 < cube.1 { gray.0 } else { cube.0 }
+}
+
+// Squared Euclidean distance, which is enough for picking the closest color
+fn distance(a: Rgb, b: Rgb) -> u32 {
+    let d = |x: u8, y: u8| (x.abs_diff(y) as u32).pow(2);
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
@@ -1,19 +1,64 @@
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt, fs, io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+use strum::EnumMessage as _;
 use strum_macros::{Display, EnumMessage};
-use validator::Validate;
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize, Validate)]
+use crate::color_space::parse_hex;
+use crate::image::{ArtPalette, Rgb};
+
+const CONFIG_ENV_VAR: &str = "SPOTIFYFETCH_CONFIG";
+const FORMAT_ENV_VAR: &str = "SPOTIFYFETCH_FORMAT";
+const MIN_TEXT_WIDTH: u16 = 20;
+const MAX_REASONABLE_WIDTH: u16 = 200;
+// The only source for these bounds: they are used for clamping, `config set` and `config list`
+const IMAGE_WIDTH_RANGE: RangeInclusive<u16> = 25..=40;
+const LIST_COUNT_RANGE: RangeInclusive<u16> = 1..=200;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Config {
     pub offset_x: u16,
     pub offset_y: u16,
     pub gap: u16,
     pub image_view: ItemType,
-    #[validate(range(min = 25, max = 40))]
+    pub image_position: ImagePosition,
+    pub caption_position: CaptionPosition,
     pub image_width: u16,
+    pub image_border: bool,
+    pub image_border_color: Option<Rgb>,
+    pub preferred_image_size: PreferredImageSize,
+    pub ascii_palette: AsciiPalette,
     pub list_view: ItemType,
-    #[validate(range(min = 1, max = 20))]
     pub list_count: u16,
+    pub list_alignment: Alignment,
+    pub list_border: bool,
+    pub dynamic_colors: bool,
+    pub color_track_names: Option<String>,
+    pub color_artist_names: Option<String>,
+    pub show_album_type: bool,
+    // Drops tracks from albums already in the list, so fewer than list_count tracks may be shown
+    pub deduplicate_albums: bool,
+    // Shows one artist per primary (first listed) genre, which changes which artists appear
+    pub deduplicate_genres: bool,
+    pub use_emoji: bool,
+    pub max_text_lines: Option<usize>,
     pub time_range: TimeRange,
+    pub format: Format,
+    pub request_timeout_secs: u64,
+    pub auth_port: u16,
+    pub startup_fetch: bool,
+    pub time_range_overrides: HashMap<TimeRange, PartialConfig>,
+    // Set from --quiet, never read from or written to the config file
+    #[serde(skip)]
+    pub quiet: bool,
 }
 
 impl Default for Config {
@@ -23,49 +68,633 @@ impl Default for Config {
             offset_y: 0,
             gap: 5,
             image_view: ItemType::Track,
+            image_position: ImagePosition::Left,
+            caption_position: CaptionPosition::BelowImage,
             image_width: 30,
+            image_border: false,
+            image_border_color: None,
+            preferred_image_size: PreferredImageSize::Auto,
+            ascii_palette: AsciiPalette::Ascii,
             list_view: ItemType::Artist,
             list_count: 10,
+            list_alignment: Alignment::Left,
+            list_border: false,
+            dynamic_colors: false,
+            color_track_names: None,
+            color_artist_names: None,
+            show_album_type: false,
+            deduplicate_albums: false,
+            deduplicate_genres: false,
+            use_emoji: true,
+            max_text_lines: None,
             time_range: TimeRange::Medium,
+            format: Format::Human,
+            request_timeout_secs: 10,
+            auth_port: 8888,
+            startup_fetch: false,
+            time_range_overrides: HashMap::new(),
+            quiet: false,
         }
     }
 }
 
 impl Config {
-    pub fn load() -> Self {
-        let config: Self = match confy::load("spotifyfetch", "config") {
-            Ok(config) => config,
+    pub fn load(time_range: Option<TimeRange>, format: Option<Format>) -> Self {
+        let config = match Self::get_config_path() {
+            Ok(path) => Self::read_from(&path),
             Err(err) => {
-                eprintln!("Failed to load config: {}", err);
+                tracing::warn!("Failed to read config: {}", err);
                 Config::default()
             }
         };
-        match config.validate() {
-            Ok(()) => config,
+        let mut config = Self::apply_env_overrides(config);
+        config.apply_format_env();
+        if let Some(time_range) = time_range {
+            config.time_range = time_range;
+        }
+        if let Some(format) = format {
+            config.format = format;
+        }
+        config.apply_time_range_override();
+        if let Err(warnings) = config.sanitize() {
+            for warning in warnings {
+                tracing::warn!("{}", warning);
+            }
+        }
+        if let Err(warnings) = config.validate_cross_field() {
+            for warning in warnings {
+                tracing::warn!("{}", warning);
+            }
+        }
+        if let Ok((term_cols, term_rows)) = crossterm::terminal::size()
+            && let Err(warnings) = config.validate_terminal_context(term_cols, term_rows)
+        {
+            for warning in warnings {
+                tracing::warn!("{}", warning);
+            }
+        }
+        config
+    }
+
+    // Read the config file, creating it with the defaults if it doesn't exist yet
+    fn read_from(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(contents) => match toml::from_str(&contents) {
+                Ok(config) => config,
+                Err(err) => {
+                    Self::report_parse_error(&contents, &err);
+                    // Keep every field that did parse, like an unknown enum variant in one field
+                    Self::from_toml_lenient(&contents)
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let config = Config::default();
+                match config.save_to(path) {
+                    Ok(()) => tracing::info!("Created default config at {}", path.display()),
+                    Err(err) => tracing::warn!("Failed to create config: {}", err),
+                }
+                config
+            }
             Err(err) => {
-                eprintln!("Invalid config: {}", err);
+                tracing::warn!("Failed to read config: {}", err);
                 Config::default()
             }
         }
     }
 
+    // Values from SPOTIFYFETCH_CONFIG (a JSON object) take precedence over the config file
+    fn apply_env_overrides(config: Self) -> Self {
+        let Ok(env_json) = env::var(CONFIG_ENV_VAR) else {
+            return config;
+        };
+        let merged = serde_json::from_str::<serde_json::Value>(&env_json).and_then(|overrides| {
+            let mut merged = serde_json::to_value(&config)?;
+            if let (Some(merged), Some(overrides)) = (merged.as_object_mut(), overrides.as_object())
+            {
+                for (key, value) in overrides {
+                    merged.insert(key.clone(), value.clone());
+                }
+            }
+            serde_json::from_value::<Self>(merged)
+        });
+        match merged {
+            Ok(merged) => merged,
+            Err(err) => {
+                tracing::warn!("Invalid {}: {}", CONFIG_ENV_VAR, err);
+                config
+            }
+        }
+    }
+
+    // A shorthand for scripts, so they don't have to build a JSON object for SPOTIFYFETCH_CONFIG
+    fn apply_format_env(&mut self) {
+        let Ok(format) = env::var(FORMAT_ENV_VAR) else {
+            return;
+        };
+        match Format::from_str(&format, true) {
+            Ok(format) => self.format = format,
+            Err(err) => tracing::warn!("Invalid {}: {}", FORMAT_ENV_VAR, err),
+        }
+    }
+
+    fn apply_time_range_override(&mut self) {
+        let Some(overrides) = self.time_range_overrides.get(&self.time_range).cloned() else {
+            return;
+        };
+        self.offset_x = overrides.offset_x.unwrap_or(self.offset_x);
+        self.offset_y = overrides.offset_y.unwrap_or(self.offset_y);
+        self.gap = overrides.gap.unwrap_or(self.gap);
+        self.image_width = overrides.image_width.unwrap_or(self.image_width);
+        self.list_count = overrides.list_count.unwrap_or(self.list_count);
+    }
+
+    fn ranged_fields(&mut self) -> [(&'static str, &mut u16, RangeInclusive<u16>); 2] {
+        [
+            ("image_width", &mut self.image_width, IMAGE_WIDTH_RANGE),
+            ("list_count", &mut self.list_count, LIST_COUNT_RANGE),
+        ]
+    }
+
+    fn range_for(key: &str) -> Option<RangeInclusive<u16>> {
+        match key {
+            "image_width" => Some(IMAGE_WIDTH_RANGE),
+            "list_count" => Some(LIST_COUNT_RANGE),
+            _ => None,
+        }
+    }
+
+    // Clamp out of range values to the nearest valid one
+    pub fn sanitize(&mut self) -> Result<(), Vec<ConfigWarning>> {
+        let mut warnings = Vec::new();
+        for (field, value, range) in self.ranged_fields() {
+            let clamped = (*value).clamp(*range.start(), *range.end());
+            if clamped != *value {
+                warnings.push(ConfigWarning::Clamped(field, *value, range));
+                *value = clamped;
+            }
+        }
+
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+
+    // `config set` rejects out of range values instead of silently clamping them
+    fn check_ranges(&mut self) -> Result<(), String> {
+        for (field, value, range) in self.ranged_fields() {
+            if !range.contains(value) {
+                return Err(format!(
+                    "{} is {}, but must be between {} and {}",
+                    field,
+                    value,
+                    range.start(),
+                    range.end()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_cross_field(&self) -> Result<(), Vec<ConfigWarning>> {
+        let mut warnings = Vec::new();
+        if self.image_view == self.list_view && self.image_view != ItemType::None {
+            warnings.push(ConfigWarning::SameViews(self.image_view));
+        }
+        let min_width = self
+            .image_width
+            .saturating_add(self.offset_x)
+            .saturating_add(self.gap)
+            .saturating_add(MIN_TEXT_WIDTH);
+        if min_width > MAX_REASONABLE_WIDTH {
+            warnings.push(ConfigWarning::TooWide(min_width));
+        }
+        for (field, hex) in [
+            ("color_track_names", &self.color_track_names),
+            ("color_artist_names", &self.color_artist_names),
+        ] {
+            if let Some(hex) = hex
+                && parse_hex(hex).is_none()
+            {
+                warnings.push(ConfigWarning::InvalidColor(field, hex.clone()));
+            }
+        }
+
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+
+    // The terminal size is only known at runtime, so offsets are clamped here instead of in sanitize
+    pub fn validate_terminal_context(
+        &mut self,
+        term_cols: u16,
+        term_rows: u16,
+    ) -> Result<(), Vec<ConfigWarning>> {
+        let mut warnings = Vec::new();
+        if term_cols > 0 && self.offset_x >= term_cols {
+            warnings.push(ConfigWarning::OffsetOffScreen(
+                "offset_x",
+                self.offset_x,
+                term_cols,
+            ));
+            self.offset_x = term_cols - 1;
+        }
+        if term_rows > 0 && self.offset_y >= term_rows {
+            warnings.push(ConfigWarning::OffsetOffScreen(
+                "offset_y",
+                self.offset_y,
+                term_rows,
+            ));
+            self.offset_y = term_rows - 1;
+        }
+
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+
+    pub fn get_config_path() -> Result<PathBuf, Box<dyn Error>> {
+        let mut path = dirs::config_dir().ok_or("Could not find config directory")?;
+        path.push("spotifyfetch");
+        path.push("config.toml");
+        Ok(path)
+    }
+
+    /// Reads the config file as it is on disk, without environment or command line overrides.
+    /// Unlike `load`, a file that fails to parse is an error rather than replaced by the defaults.
+    pub fn load_file() -> Result<Self, Box<dyn Error>> {
+        let path = Self::get_config_path()?;
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Reads the config file one field at a time, keeping the default for any field that fails
+    /// to parse, so a partly broken file can still be inspected.
+    pub fn load_file_lenient() -> Self {
+        match Self::get_config_path().and_then(|path| Ok(fs::read_to_string(path)?)) {
+            Ok(contents) => Self::from_toml_lenient(&contents),
+            Err(_) => Config::default(),
+        }
+    }
+
+    fn from_toml_lenient(contents: &str) -> Self {
+        let Ok(table) = contents.parse::<toml::Table>() else {
+            return Config::default();
+        };
+        let Ok(mut fields) = serde_json::to_value(Config::default()) else {
+            return Config::default();
+        };
+        for (key, value) in table {
+            let Ok(value) = serde_json::to_value(value) else {
+                continue;
+            };
+            let mut candidate = fields.clone();
+            candidate[key.as_str()] = value;
+            if serde_json::from_value::<Self>(candidate.clone()).is_ok() {
+                fields = candidate;
+            }
+        }
+        serde_json::from_value(fields).unwrap_or_default()
+    }
+
+    /// Every field with its current value and the values it accepts, sorted by name.
+    pub fn list_fields(&self) -> Result<Vec<(String, String, String)>, Box<dyn Error>> {
+        let serde_json::Value::Object(fields) = serde_json::to_value(self)? else {
+            return Err("Config did not serialize to a table".into());
+        };
+        Ok(fields
+            .iter()
+            .map(|(key, value)| {
+                let valid_range = match Self::range_for(key) {
+                    Some(range) => format!("{} to {}", range.start(), range.end()),
+                    None => Self::valid_range(key).to_string(),
+                };
+                (key.clone(), Self::display_value(value), valid_range)
+            })
+            .collect())
+    }
+
+    fn valid_range(key: &str) -> &'static str {
+        match key {
+            "image_view" | "list_view" => "Artist, Track, Album, RecentlyPlayed, None",
+            "image_position" => "Left, Right",
+            "caption_position" => "BelowImage, AboveImage, BelowList, None",
+            "ascii_palette" => "Ascii, Block, Dots, Math",
+            "preferred_image_size" => "Auto, Small, Medium, Large",
+            "list_alignment" => "Left, Center, Right",
+            "time_range" => "Short, Medium, Long",
+            "format" => "Human, Json, Tsv",
+            "offset_x" | "offset_y" | "gap" => "0 to 65535",
+            "image_border_color" => "[r, g, b] or unset",
+            "color_track_names" | "color_artist_names" => "hex color like \"#1DB954\" or unset",
+            "deduplicate_albums" => "true, false (may show fewer than list_count tracks)",
+            "deduplicate_genres" => "true, false (may show fewer than list_count artists)",
+            "max_text_lines" => "any number or unset",
+            "request_timeout_secs" => "any number of seconds",
+            "auth_port" => "1 to 65535, registered as http://localhost:<port>/callback",
+            "time_range_overrides" => "table per time range",
+            _ => "true, false",
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        self.save_to(&Self::get_config_path()?)
+    }
+
+    /// Sets a top level field from its string form, returning the old and new values for display.
+    /// The result is validated before it replaces the current config.
+    pub fn set_field(
+        &mut self,
+        key: &str,
+        value: &str,
+    ) -> Result<(String, String), Box<dyn Error>> {
+        let mut fields = serde_json::to_value(&*self)?;
+        let field = fields
+            .get_mut(key)
+            .ok_or_else(|| format!("Unknown config field `{}`", key))?;
+        if field.is_object() {
+            return Err(format!("`{}` can only be changed in the config file", key).into());
+        }
+        let old_value = Self::display_value(field);
+
+        // Anything that isn't valid JSON, like an enum variant or a hex color, is taken as a string
+        *field = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        let mut updated: Self = serde_json::from_value(fields)
+            .map_err(|err| format!("Invalid value for `{}`: {}", key, err))?;
+        updated.check_ranges()?;
+
+        let new_value = Self::display_value(&serde_json::to_value(&updated)?[key]);
+        *self = updated;
+        Ok((old_value, new_value))
+    }
+
+    pub fn get_field(&self, key: &str) -> Result<String, Box<dyn Error>> {
+        let fields = serde_json::to_value(self)?;
+        let field = fields
+            .get(key)
+            .ok_or_else(|| format!("Unknown config field `{}`", key))?;
+        Ok(Self::display_value(field))
+    }
+
+    // Strings are shown without quotes and unset options as nothing, so values work in scripts
+    fn display_value(value: &serde_json::Value) -> String {
+        match value {
+            serde_json::Value::String(string) => string.clone(),
+            serde_json::Value::Null => String::new(),
+            value => value.to_string(),
+        }
+    }
+
+    fn save_to(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn report_parse_error(contents: &str, err: &toml::de::Error) {
+        let Some(span) = err.span() else {
+            tracing::warn!("Invalid config: {}", err.message());
+            return;
+        };
+        let line_number = contents[..span.start].matches('\n').count() + 1;
+        let field = contents
+            .lines()
+            .nth(line_number - 1)
+            .and_then(|line| line.split('=').next())
+            .map(str::trim)
+            .unwrap_or_default();
+        tracing::warn!(
+            "Invalid config at line {} (field `{}`): {}",
+            line_number,
+            field,
+            err.message()
+        );
+    }
+
+    // Albums come from the top tracks, so they need the same data as tracks. Recently played
+    // tracks are fetched separately, see get_recently_played_count.
     pub fn get_item_count(&self) -> (u16, u16) {
+        use ItemType::{Album, Artist, None, RecentlyPlayed, Track};
         match (self.image_view, self.list_view) {
-            (ItemType::Track, ItemType::Artist) => (1, self.list_count),
-            (ItemType::Track, ItemType::Track) => (self.list_count, 0),
-            (ItemType::Artist, ItemType::Track) => (self.list_count, 1),
-            (ItemType::Artist, ItemType::Artist) => (0, self.list_count),
+            (Track | Album, Artist) => (1, self.list_count),
+            (Track | Album, Track | Album) => (self.list_count, 0),
+            (Artist, Track | Album) => (self.list_count, 1),
+            (Artist, Artist) => (0, self.list_count),
+            (Track | Album, None | RecentlyPlayed) => (1, 0),
+            (Artist, None | RecentlyPlayed) => (0, 1),
+            (None | RecentlyPlayed, Track | Album) => (self.list_count, 0),
+            (None | RecentlyPlayed, Artist) => (0, self.list_count),
+            (None | RecentlyPlayed, None | RecentlyPlayed) => (0, 0),
+        }
+    }
+
+    pub fn get_recently_played_count(&self) -> u16 {
+        if self.list_view == ItemType::RecentlyPlayed {
+            self.list_count
+        } else if self.image_view == ItemType::RecentlyPlayed {
+            1
+        } else {
+            0
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+// A one line summary, e.g. for pasting into issue reports
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "spotifyfetch config: time_range={} ({})",
+            format!("{:?}", self.time_range).to_lowercase(),
+            self.time_range.get_message().unwrap()
+        )?;
+        match self.list_view {
+            ItemType::Artist => write!(f, ", list={} artists", self.list_count)?,
+            ItemType::Track => write!(f, ", list={} tracks", self.list_count)?,
+            ItemType::Album => write!(f, ", list={} albums", self.list_count)?,
+            ItemType::RecentlyPlayed => {
+                write!(f, ", list={} recently played tracks", self.list_count)?
+            }
+            ItemType::None => write!(f, ", list=none")?,
+        }
+        match self.image_view {
+            ItemType::Artist => write!(f, ", image=artist image ({} cols)", self.image_width),
+            ItemType::Track => write!(f, ", image=track art ({} cols)", self.image_width),
+            ItemType::Album => write!(f, ", image=album art ({} cols)", self.image_width),
+            ItemType::RecentlyPlayed => write!(
+                f,
+                ", image=recently played track art ({} cols)",
+                self.image_width
+            ),
+            ItemType::None => write!(f, ", image=none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PartialConfig {
+    pub offset_x: Option<u16>,
+    pub offset_y: Option<u16>,
+    pub gap: Option<u16>,
+    pub image_width: Option<u16>,
+    pub list_count: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum ConfigWarning {
+    SameViews(ItemType),
+    TooWide(u16),
+    OffsetOffScreen(&'static str, u16, u16),
+    InvalidColor(&'static str, String),
+    Clamped(&'static str, u16, RangeInclusive<u16>),
+}
+
+impl fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigWarning::SameViews(item_type) => write!(
+                f,
+                "image_view and list_view are both {:?}, so only one item type is fetched",
+                item_type
+            ),
+            ConfigWarning::TooWide(width) => write!(
+                f,
+                "image_width + offset_x + gap needs at least {} columns, which is too wide for most terminals",
+                width
+            ),
+            ConfigWarning::OffsetOffScreen(field, value, limit) => write!(
+                f,
+                "{} is {}, which is off screen in a terminal of size {}, so it was clamped to {}",
+                field,
+                value,
+                limit,
+                limit - 1
+            ),
+            ConfigWarning::Clamped(field, value, range) => write!(
+                f,
+                "{} is {}, but must be between {} and {}, so it was clamped to {}",
+                field,
+                value,
+                range.start(),
+                range.end(),
+                value.clamp(range.start(), range.end())
+            ),
+            ConfigWarning::InvalidColor(field, hex) => write!(
+                f,
+                "{} is \"{}\", which is not a hex color like \"#1DB954\"",
+                field, hex
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum ItemType {
     Artist,
     Track,
+    Album,
+    RecentlyPlayed,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum ImagePosition {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum PreferredImageSize {
+    Auto,
+    Small,
+    Medium,
+    Large,
+}
+
+impl PreferredImageSize {
+    // Approximate widths of the sizes Spotify serves
+    pub fn pixel_width(&self) -> Option<u32> {
+        match self {
+            PreferredImageSize::Auto => None,
+            PreferredImageSize::Small => Some(64),
+            PreferredImageSize::Medium => Some(300),
+            PreferredImageSize::Large => Some(640),
+        }
+    }
+}
+
+// Character set for drawing the image as text when it can't be displayed as an image
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum AsciiPalette {
+    Ascii,
+    Block,
+    Dots,
+    Math,
+}
+
+impl AsciiPalette {
+    pub fn palette(&self) -> &'static ArtPalette {
+        match self {
+            AsciiPalette::Ascii => &ArtPalette::ASCII,
+            AsciiPalette::Block => &ArtPalette::BLOCK,
+            AsciiPalette::Dots => &ArtPalette::DOTS,
+            AsciiPalette::Math => &ArtPalette::MATH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum CaptionPosition {
+    BelowImage,
+    AboveImage,
+    BelowList,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+// Json and Tsv are meant for scripts, and skip everything but printing the fetched items
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+pub enum Format {
+    Human,
+    Json,
+    Tsv,
 }
 
-#[derive(Display, Debug, Clone, Copy, Deserialize, Serialize, EnumMessage)]
+// Ordered by how much listening history each range covers, so keep the variants in that order
+#[derive(
+    Display,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Deserialize,
+    Serialize,
+    EnumMessage,
+    ValueEnum,
+)]
 pub enum TimeRange {
     #[strum(to_string = "short_term", message = "4 weeks")]
     Short,
@@ -0,0 +1,242 @@
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use chrono::Local;
+use clap::ValueEnum;
+use serde_json::json;
+use std::{error::Error, fs, path::PathBuf};
+use strum::EnumMessage;
+
+use crate::api::{Artist, Track};
+use crate::config::TimeRange;
+use crate::image::Image;
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; background: #121212; color: #fff; margin: 2em; } \
+.container { display: flex; gap: 2em; align-items: flex-start; } \
+.container img { width: 300px; border-radius: 8px; }";
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Md,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn exporter(&self) -> Box<dyn Exporter> {
+        match self {
+            ExportFormat::Json => Box::new(JsonRenderer),
+            ExportFormat::Csv => Box::new(CsvExporter),
+            ExportFormat::Md => Box::new(MarkdownExporter),
+            ExportFormat::Html => Box::new(HtmlExporter),
+        }
+    }
+}
+
+pub struct ExportData<'a> {
+    pub time_range: TimeRange,
+    pub tracks: &'a [Track],
+    pub artists: &'a [Artist],
+    pub image: Option<&'a Image>,
+}
+
+pub trait Exporter {
+    fn extension(&self) -> &'static str;
+    fn export(&self, data: &ExportData) -> Result<String, Box<dyn Error>>;
+}
+
+pub fn write_export(
+    exporter: &dyn Exporter,
+    data: &ExportData,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let contents = exporter.export(data)?;
+    let Some(mut path) = output else {
+        print!("{}", contents);
+        return Ok(());
+    };
+
+    if path.is_dir() {
+        path.push(format!(
+            "spotifyfetch-{}-{}.{}",
+            data.time_range,
+            Local::now().format("%Y-%m-%d"),
+            exporter.extension()
+        ));
+    }
+    fs::write(&path, contents)?;
+    eprintln!("Exported to {}", path.display());
+    Ok(())
+}
+
+pub struct JsonRenderer;
+
+impl Exporter for JsonRenderer {
+    fn extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn export(&self, data: &ExportData) -> Result<String, Box<dyn Error>> {
+        let tracks: Vec<_> = data
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(i, track)| {
+                json!({
+                    "rank": i + 1,
+                    "name": track.name,
+                    "artists": track
+                        .artists
+                        .iter()
+                        .map(|a| json!({ "id": a.id, "name": a.name }))
+                        .collect::<Vec<_>>(),
+                    "album": { "id": track.album.id, "name": track.album.name },
+                    "url": track.external_urls.spotify,
+                    "linked_from": track
+                        .linked_from
+                        .as_ref()
+                        .map(|linked| json!({ "id": linked.id, "uri": linked.uri })),
+                })
+            })
+            .collect();
+        let artists: Vec<_> = data
+            .artists
+            .iter()
+            .enumerate()
+            .map(|(i, artist)| {
+                json!({
+                    "rank": i + 1,
+                    "id": artist.id,
+                    "name": artist.name,
+                    "url": artist.external_urls.spotify,
+                })
+            })
+            .collect();
+
+        let value = json!({
+            "time_range": data.time_range.to_string(),
+            "tracks": tracks,
+            "artists": artists,
+        });
+        Ok(serde_json::to_string_pretty(&value)? + "\n")
+    }
+}
+
+pub struct CsvExporter;
+
+impl CsvExporter {
+    fn escape(field: &str) -> String {
+        if field.contains([',', '"', '\n']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
+}
+
+impl Exporter for CsvExporter {
+    fn extension(&self) -> &'static str {
+        "csv"
+    }
+
+    fn export(&self, data: &ExportData) -> Result<String, Box<dyn Error>> {
+        let mut csv = String::from("type,rank,name,artists,album\n");
+        for (i, track) in data.tracks.iter().enumerate() {
+            csv.push_str(&format!(
+                "track,{},{},{},{}\n",
+                i + 1,
+                Self::escape(&track.name),
+                Self::escape(&track.artist_names()),
+                Self::escape(&track.album.name)
+            ));
+        }
+        for (i, artist) in data.artists.iter().enumerate() {
+            csv.push_str(&format!(
+                "artist,{},{},,\n",
+                i + 1,
+                Self::escape(&artist.name)
+            ));
+        }
+        Ok(csv)
+    }
+}
+
+pub struct MarkdownExporter;
+
+impl Exporter for MarkdownExporter {
+    fn extension(&self) -> &'static str {
+        "md"
+    }
+
+    fn export(&self, data: &ExportData) -> Result<String, Box<dyn Error>> {
+        let mut md = format!(
+            "# Spotify stats from the most recent {}\n",
+            data.time_range.get_message().unwrap()
+        );
+        md.push_str("\n## Top Tracks\n\n");
+        for (i, track) in data.tracks.iter().enumerate() {
+            md.push_str(&format!("{}. {}\n", i + 1, track.format_track_display()));
+        }
+        md.push_str("\n## Top Artists\n\n");
+        for (i, artist) in data.artists.iter().enumerate() {
+            md.push_str(&format!("{}. {}\n", i + 1, artist.name));
+        }
+        Ok(md)
+    }
+}
+
+pub struct HtmlExporter;
+
+impl HtmlExporter {
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+}
+
+impl Exporter for HtmlExporter {
+    fn extension(&self) -> &'static str {
+        "html"
+    }
+
+    fn export(&self, data: &ExportData) -> Result<String, Box<dyn Error>> {
+        let title = format!(
+            "Spotify stats from the most recent {}",
+            data.time_range.get_message().unwrap()
+        );
+        let mut html = format!(
+            "<!DOCTYPE html>\n<!-- Time range: {}, generated at {} -->\n",
+            data.time_range,
+            Local::now().to_rfc3339()
+        );
+        html.push_str(&format!(
+            "<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n<div class=\"container\">\n",
+            title, HTML_STYLE, title
+        ));
+
+        // Inline the album art so the file can be viewed without network access
+        if let Some(image) = data.image {
+            let image_bytes = fs::read(&image.path)?;
+            html.push_str(&format!(
+                "<img src=\"data:{};base64,{}\" alt=\"Album art\">\n",
+                image.mime_type(),
+                STANDARD.encode(image_bytes)
+            ));
+        }
+
+        html.push_str("<div>\n<h2>Top Tracks</h2>\n<ol>\n");
+        for track in data.tracks {
+            html.push_str(&format!(
+                "<li>{}</li>\n",
+                Self::escape(&track.format_track_display())
+            ));
+        }
+        html.push_str("</ol>\n<h2>Top Artists</h2>\n<ol>\n");
+        for artist in data.artists {
+            html.push_str(&format!("<li>{}</li>\n", Self::escape(&artist.name)));
+        }
+        html.push_str("</ol>\n</div>\n</div>\n</body>\n</html>\n");
+        Ok(html)
+    }
+}
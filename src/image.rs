@@ -1,72 +1,605 @@
-use image::GenericImageView;
+use image::imageops::{self, FilterType};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{error::Error, fs, path::PathBuf};
+use std::{
+    cell::OnceCell,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tokio::sync::watch;
 
 use crate::api::Image as SpotifyImage;
+use crate::config::Config;
 
+const CACHE_VERSION: &str = "v1";
+const DEFAULT_CELL_PIXEL_WIDTH: u32 = 8;
+// Extensions downloaded images may be cached with, see extension_for_content_type
+const CACHED_EXTENSIONS: [&str; 4] = ["jpg", "png", "webp", "gif"];
+
+pub type Rgb = (u8, u8, u8);
+
+/// Characters used for character art, ordered from darkest to brightest.
+pub struct ArtPalette {
+    pub chars: &'static str,
+}
+
+impl ArtPalette {
+    pub const ASCII: ArtPalette = ArtPalette {
+        chars: " .:-=+*#%@",
+    };
+    pub const BLOCK: ArtPalette = ArtPalette {
+        chars: " ░▒▓█"
+    };
+    pub const DOTS: ArtPalette = ArtPalette { chars: " ·•●" };
+    pub const MATH: ArtPalette = ArtPalette {
+        chars: " ∘∙○◉●"
+    };
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DownloadProgress {
+    pub downloaded_bytes: u64,
+    pub total_bytes: Option<u64>,
+}
+
+// Pixel dimensions stored next to a cached image so they can be read without decoding it
+#[derive(Serialize, Deserialize)]
+struct ImageMeta {
+    width: u32,
+    height: u32,
+}
+
+/// A cached album or artist image on disk.
+///
+/// Animated GIFs are passed to viuer as-is, so they animate in terminals that support it and
+/// show a static frame everywhere else.
 pub struct Image {
     pub path: PathBuf,
+    dimensions: OnceCell<(u32, u32)>,
 }
 
 impl Image {
-    pub async fn new(images: &[SpotifyImage]) -> Result<Self, Box<dyn Error>> {
-        let url = Self::get_best_image_url(images).ok_or("No images found")?;
-        let path = Self::download_image(&url).await?;
-        Ok(Image { path })
+    pub fn from_path(path: PathBuf) -> Self {
+        Image {
+            path,
+            dimensions: OnceCell::new(),
+        }
+    }
+
+    /// Downloads the image best suited to `target_display_width_cols` terminal columns, or the
+    /// largest one available when it is `None`, e.g. for images that aren't shown in a terminal.
+    pub async fn new(
+        images: &[SpotifyImage],
+        target_display_width_cols: Option<u16>,
+        config: &Config,
+        client: &reqwest::Client,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::fetch(images, target_display_width_cols, config, client, None).await
     }
 
-    fn get_best_image_url(images: &[SpotifyImage]) -> Option<String> {
+    /// Like `new`, but reports how much of the image has been downloaded through `progress_tx`.
+    /// Nothing is sent when the image is already cached.
+    pub async fn new_with_progress(
+        images: &[SpotifyImage],
+        config: &Config,
+        client: &reqwest::Client,
+        progress_tx: watch::Sender<DownloadProgress>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::fetch(
+            images,
+            Some(config.image_width),
+            config,
+            client,
+            Some(&progress_tx),
+        )
+        .await
+    }
+
+    async fn fetch(
+        images: &[SpotifyImage],
+        target_display_width_cols: Option<u16>,
+        config: &Config,
+        client: &reqwest::Client,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = match (
+            config.preferred_image_size.pixel_width(),
+            target_display_width_cols,
+        ) {
+            (Some(width), _) => {
+                let image = Self::closest_image(images, width).ok_or("No images found")?;
+                Self::download_image(&image.url, client, progress).await?
+            }
+            (None, Some(width_cols)) => {
+                let image =
+                    Self::best_image_for_display(images, width_cols, DEFAULT_CELL_PIXEL_WIDTH)
+                        .ok_or("No images found")?;
+                let target_width = width_cols as u32 * DEFAULT_CELL_PIXEL_WIDTH;
+                Self::resize_and_cache(image, target_width, client, progress).await?
+            }
+            (None, None) => {
+                let image = images
+                    .iter()
+                    .max_by_key(|img| img.width * img.height)
+                    .ok_or("No images found")?;
+                Self::download_image(&image.url, client, progress).await?
+            }
+        };
+        Ok(Image::from_path(path))
+    }
+
+    // Start downloading on the runtime right away so the caller can await it once it's needed
+    pub fn prefetch(
+        images: &[SpotifyImage],
+        config: &Config,
+        client: &reqwest::Client,
+        progress_tx: watch::Sender<DownloadProgress>,
+    ) -> impl Future<Output = Result<Self, Box<dyn Error>>> + use<> {
+        let images = images.to_vec();
+        let config = config.clone();
+        let client = client.clone();
+        let handle = tokio::spawn(async move {
+            Self::new_with_progress(&images, &config, &client, progress_tx)
+                .await
+                .map_err(|err| err.to_string())
+        });
+        async move { Ok(handle.await??) }
+    }
+
+    // Pick the smallest image that still covers the displayed width, or the largest one available
+    pub fn best_image_for_display(
+        images: &[SpotifyImage],
+        term_cols: u16,
+        cell_pixel_width: u32,
+    ) -> Option<&SpotifyImage> {
+        let target_width = term_cols as u32 * cell_pixel_width;
         images
             .iter()
-            .max_by_key(|img| img.width * img.height)
-            .map(|img| img.url.clone())
+            .filter(|img| img.width >= target_width)
+            .min_by_key(|img| img.width)
+            .or_else(|| images.iter().max_by_key(|img| img.width * img.height))
+    }
+
+    fn closest_image(images: &[SpotifyImage], width: u32) -> Option<&SpotifyImage> {
+        images.iter().min_by_key(|img| img.width.abs_diff(width))
     }
 
     fn get_image_cache_dir() -> Result<PathBuf, Box<dyn Error>> {
-        let mut path = dirs::cache_dir().ok_or("Could not find cache directory")?;
+        Self::get_image_cache_dir_for(dirs::cache_dir().ok_or("Could not find cache directory")?)
+    }
+
+    fn get_image_cache_dir_for(base: PathBuf) -> Result<PathBuf, Box<dyn Error>> {
+        let mut path = base;
         path.push("spotifyfetch");
         path.push("images");
-        fs::create_dir_all(&path)?;
+        path.push(CACHE_VERSION);
+        // What is cached says something about what the user listens to, so keep it private
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(&path)?;
         Ok(path)
     }
 
-    fn hash_url(url: &str) -> String {
+    fn cache_filename(url: &str, ext: &str) -> String {
         let mut hasher = Sha256::new();
         hasher.update(url.as_bytes());
         let result = hasher.finalize();
-        format!("{:x}.jpg", result)
+        format!("{:x}.{}", result, ext)
+    }
+
+    fn extension_for_content_type(content_type: Option<&str>) -> &'static str {
+        // Drop parameters like "; charset=binary"
+        let mime = content_type.and_then(|value| value.split(';').next());
+        match mime.map(str::trim) {
+            Some("image/jpeg") => "jpg",
+            Some("image/png") => "png",
+            Some("image/webp") => "webp",
+            Some("image/gif") => "gif",
+            Some(mime) => {
+                tracing::warn!("Unrecognized image content type {}, assuming JPEG", mime);
+                "jpg"
+            }
+            None => {
+                tracing::warn!("Image has no content type, assuming JPEG");
+                "jpg"
+            }
+        }
     }
 
-    pub async fn download_image(url: &str) -> Result<PathBuf, Box<dyn Error>> {
+    // Spotify only serves a few sizes, so keep a copy scaled down to the width actually displayed
+    pub async fn resize_and_cache(
+        spotify_image: &SpotifyImage,
+        target_width_px: u32,
+        client: &reqwest::Client,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        if spotify_image.width <= target_width_px {
+            return Self::download_image(&spotify_image.url, client, progress).await;
+        }
+
+        // The resized copy is always encoded as JPEG, whatever the original was
+        let filename = Self::cache_filename(
+            &format!("{}?width={}", spotify_image.url, target_width_px),
+            "jpg",
+        );
+        let resized = Image::from_path(Self::get_image_cache_dir()?.join(filename));
+        if resized.path.exists() && resized.verify_cached().is_ok() {
+            return Ok(resized.path);
+        }
+
+        let original =
+            Image::from_path(Self::download_image(&spotify_image.url, client, progress).await?);
+        // Resizing would only keep the first frame of an animation
+        if original.is_gif()? {
+            return Ok(original.path);
+        }
+
+        let img = image::open(&original.path)?.to_rgb8();
+        let target_height =
+            (img.height() as u64 * target_width_px as u64 / img.width() as u64).max(1) as u32;
+        // Saved under a temporary name first, so an interrupted run never leaves half a file
+        // behind under the cached name
+        let partial_path = Self::partial_path(&resized.path);
+        imageops::resize(&img, target_width_px, target_height, FilterType::Triangle)
+            .save_with_format(&partial_path, image::ImageFormat::Jpeg)?;
+        fs::rename(&partial_path, &resized.path)?;
+        // The sidecar is only an optimization, so failing to write it is not an error
+        if let Err(err) = resized.write_meta() {
+            tracing::warn!("Could not write image metadata: {}", err);
+        }
+
+        Ok(resized.path)
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip(client, progress),
+        fields(url = %url, cache_hit = tracing::field::Empty, bytes = tracing::field::Empty)
+    )]
+    pub async fn download_image(
+        url: &str,
+        client: &reqwest::Client,
+        progress: Option<&watch::Sender<DownloadProgress>>,
+    ) -> Result<PathBuf, Box<dyn Error>> {
         let cache_dir = Self::get_image_cache_dir()?;
-        let filename = Self::hash_url(url);
-        let file_path = cache_dir.join(&filename);
+        // The extension is only known once the image has been downloaded
+        let cached_path = CACHED_EXTENSIONS
+            .iter()
+            .map(|ext| cache_dir.join(Self::cache_filename(url, ext)))
+            .find(|path| path.exists());
 
-        if file_path.exists() {
-            return Ok(file_path);
+        if let Some(file_path) = cached_path {
+            let cached = Image::from_path(file_path.clone());
+            // Only the header is read here, the full decode already happened after the download
+            match cached.verify_cached() {
+                Ok(()) => {
+                    tracing::Span::current().record("cache_hit", true);
+                    return Ok(file_path);
+                }
+                Err(err) => {
+                    tracing::warn!("{}, downloading it again", err);
+                    fs::remove_file(&file_path)?;
+                }
+            }
         }
+        tracing::Span::current().record("cache_hit", false);
 
-        let client = reqwest::Client::new();
-        let response = client.get(url).send().await?;
+        // The client defaults to JSON for the API, which the CDN has no use for
+        let mut response = client
+            .get(url)
+            .header(reqwest::header::ACCEPT, "image/*")
+            .send()
+            .await?;
 
         if !response.status().is_success() {
             return Err(format!("Failed to download image: {}", response.status()).into());
         }
+        let ext = Self::extension_for_content_type(
+            response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok()),
+        );
+        let file_path = cache_dir.join(Self::cache_filename(url, ext));
+        // The cache stays keyed by the original URL, since that is all that is known up front
+        // on the next run; Spotify's image URLs are content addressed, so a redirect only
+        // changes which CDN node serves the file
+        if response.url().as_str() != url {
+            tracing::debug!("Image request redirected to {}", response.url());
+        }
 
-        let bytes = response.bytes().await?;
-        fs::write(&file_path, bytes)?;
+        let total_bytes = response.content_length();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            bytes.extend_from_slice(&chunk);
+            if let Some(progress) = progress {
+                progress.send_replace(DownloadProgress {
+                    downloaded_bytes: bytes.len() as u64,
+                    total_bytes,
+                });
+            }
+        }
+        tracing::Span::current().record("bytes", bytes.len());
+        // Only a complete, decodable download is moved to the cached name
+        let partial = Image::from_path(Self::partial_path(&file_path));
+        fs::write(&partial.path, bytes)?;
+        // The CDN can answer with an HTML error page, which should not end up in the cache
+        if let Err(err) = partial.verify_integrity() {
+            fs::remove_file(&partial.path)?;
+            return Err(err.into());
+        }
+        fs::rename(&partial.path, &file_path)?;
+        let downloaded = Image::from_path(file_path.clone());
+        if let Err(err) = downloaded.write_meta() {
+            tracing::warn!("Could not write image metadata: {}", err);
+        }
 
         Ok(file_path)
     }
 
-    pub fn get_terminal_height(&self, width_columns: u32) -> Result<u32, Box<dyn Error>> {
-        let img = image::open(&self.path)?;
-        let (img_width, img_height) = img.dimensions();
+    /// MIME type matching the file's extension, the reverse of `extension_for_content_type`.
+    pub fn mime_type(&self) -> &'static str {
+        match self.path.extension().and_then(|ext| ext.to_str()) {
+            Some("png") => "image/png",
+            Some("webp") => "image/webp",
+            Some("gif") => "image/gif",
+            _ => "image/jpeg",
+        }
+    }
 
-        // Each terminal row is roughly twice as tall as it is wide
-        let aspect_ratio = img_height as f32 / img_width as f32;
-        let term_height = (width_columns as f32 * aspect_ratio / 2.0).ceil() as u32;
+    // Keeps the real extension last, since the image crate picks the format by extension
+    fn partial_path(path: &Path) -> PathBuf {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("jpg");
+        path.with_extension(format!("part.{}", ext))
+    }
+
+    fn meta_path(&self) -> PathBuf {
+        self.path.with_extension("meta")
+    }
+
+    fn write_meta(&self) -> Result<(), Box<dyn Error>> {
+        let (width, height) = image::ImageReader::open(&self.path)?
+            .with_guessed_format()?
+            .into_dimensions()?;
+        fs::write(
+            self.meta_path(),
+            serde_json::to_string(&ImageMeta { width, height })?,
+        )?;
+        Ok(())
+    }
+
+    fn read_meta(&self) -> Option<ImageMeta> {
+        let json = fs::read_to_string(self.meta_path()).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    /// Check for an image already in the cache. The sidecar is only written once the image has
+    /// been fully decoded, so with one the header just has to agree with it. Without one, as
+    /// for files cached by older versions, the whole image is decoded.
+    pub fn verify_cached(&self) -> Result<(), ImageError> {
+        let Some(meta) = self.read_meta() else {
+            return self.verify_integrity();
+        };
+        let dimensions = image::ImageReader::open(&self.path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(image::ImageError::IoError)
+            .and_then(|reader| reader.into_dimensions())
+            .map_err(|err| ImageError::ImageDecode(self.path.clone(), err))?;
+        if (meta.width, meta.height) != dimensions {
+            return Err(ImageError::MetaMismatch(self.path.clone()));
+        }
+        Ok(())
+    }
 
+    pub fn verify_integrity(&self) -> Result<(), ImageError> {
+        image::open(&self.path)
+            .map(|_| ())
+            .map_err(|err| ImageError::ImageDecode(self.path.clone(), err))
+    }
+
+    /// Width and height in pixels, read once and reused for later calls.
+    pub fn dimensions(&self) -> Result<(u32, u32), ImageError> {
+        if let Some(dimensions) = self.dimensions.get() {
+            return Ok(*dimensions);
+        }
+
+        let dimensions = match self.read_meta() {
+            Some(meta) => (meta.width, meta.height),
+            // Only the header is read here, which also works for animated GIFs
+            None => image::ImageReader::open(&self.path)
+                .and_then(|reader| reader.with_guessed_format())
+                .map_err(image::ImageError::IoError)
+                .and_then(|reader| reader.into_dimensions())
+                .map_err(|err| ImageError::ImageDecode(self.path.clone(), err))?,
+        };
+        self.dimensions.set(dimensions).ok();
+        Ok(dimensions)
+    }
+
+    /// Height divided by width.
+    pub fn aspect_ratio(&self) -> Result<f32, ImageError> {
+        let (width, height) = self.dimensions()?;
+        Ok(height as f32 / width as f32)
+    }
+
+    pub fn get_terminal_height(&self, width_columns: u32) -> Result<u32, Box<dyn Error>> {
+        // Each terminal row is roughly twice as tall as it is wide
+        let term_height = (width_columns as f32 * self.aspect_ratio()? / 2.0).ceil() as u32;
         Ok(term_height)
     }
+
+    /// Draws the image with characters from `palette`, darkest to brightest, `width` columns wide.
+    pub fn to_unicode_art(
+        &self,
+        width: u32,
+        palette: &ArtPalette,
+    ) -> Result<String, Box<dyn Error>> {
+        let height = self.get_terminal_height(width)?.max(1);
+        let img = image::open(&self.path)?
+            .resize_exact(width, height, FilterType::Triangle)
+            .to_luma8();
+        let chars: Vec<char> = palette.chars.chars().collect();
+
+        let mut art = String::new();
+        for row in img.rows() {
+            for pixel in row {
+                art.push(chars[pixel.0[0] as usize * (chars.len() - 1) / 255]);
+            }
+            art.push('\n');
+        }
+        Ok(art)
+    }
+
+    pub fn to_dominant_colors(&self, n: usize) -> Result<Vec<Rgb>, Box<dyn Error>> {
+        let img = image::open(&self.path)?.thumbnail(64, 64).to_rgb8();
+
+        // Quantize each channel to 3 bits and average the pixels that fall in each bucket
+        let mut buckets: HashMap<Rgb, (u32, u32, u32, u32)> = HashMap::new();
+        for pixel in img.pixels() {
+            let [r, g, b] = pixel.0;
+            let bucket = buckets.entry((r >> 5, g >> 5, b >> 5)).or_default();
+            bucket.0 += 1;
+            bucket.1 += r as u32;
+            bucket.2 += g as u32;
+            bucket.3 += b as u32;
+        }
+
+        let mut buckets: Vec<_> = buckets.into_values().collect();
+        buckets.sort_by(|a, b| b.0.cmp(&a.0));
+        Ok(buckets
+            .into_iter()
+            .take(n)
+            .map(|(count, r, g, b)| ((r / count) as u8, (g / count) as u8, (b / count) as u8))
+            .collect())
+    }
+
+    fn is_gif(&self) -> Result<bool, Box<dyn Error>> {
+        let mut header = [0u8; 6];
+        let bytes_read = File::open(&self.path)?.read(&mut header)?;
+        Ok(bytes_read == header.len() && (&header == b"GIF89a" || &header == b"GIF87a"))
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    ImageDecode(PathBuf, image::ImageError),
+    MetaMismatch(PathBuf),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::ImageDecode(path, err) => {
+                write!(f, "Could not decode image {}: {}", path.display(), err)
+            }
+            ImageError::MetaMismatch(path) => {
+                write!(
+                    f,
+                    "Cached image {} does not match its metadata",
+                    path.display()
+                )
+            }
+        }
+    }
+}
+
+impl Error for ImageError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_dir_is_created_under_base() {
+        let base = tempfile::tempdir().unwrap();
+        let path = Image::get_image_cache_dir_for(base.path().to_path_buf()).unwrap();
+        assert_eq!(
+            path,
+            base.path()
+                .join("spotifyfetch")
+                .join("images")
+                .join(CACHE_VERSION)
+        );
+        assert!(path.is_dir());
+
+        let again = Image::get_image_cache_dir_for(base.path().to_path_buf()).unwrap();
+        assert_eq!(again, path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cache_dir_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = tempfile::tempdir().unwrap();
+        let path = Image::get_image_cache_dir_for(base.path().to_path_buf()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn mime_type_follows_extension() {
+        for ext in CACHED_EXTENSIONS {
+            let image = Image::from_path(PathBuf::from(Image::cache_filename("url", ext)));
+            let mime = image.mime_type();
+            assert_eq!(Image::extension_for_content_type(Some(mime)), ext);
+        }
+    }
+
+    #[test]
+    fn verify_cached_checks_header_against_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let cached = Image::from_path(dir.path().join("cover.png"));
+        image::RgbImage::new(4, 2).save(&cached.path).unwrap();
+        cached.write_meta().unwrap();
+        assert!(cached.verify_cached().is_ok());
+
+        fs::write(cached.meta_path(), r#"{"width":4,"height":4}"#).unwrap();
+        assert!(matches!(
+            cached.verify_cached(),
+            Err(ImageError::MetaMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn verify_cached_decodes_truncated_image_without_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let cached = Image::from_path(dir.path().join("cover.png"));
+        image::RgbImage::new(64, 64).save(&cached.path).unwrap();
+        let bytes = fs::read(&cached.path).unwrap();
+        fs::write(&cached.path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(matches!(
+            cached.verify_cached(),
+            Err(ImageError::ImageDecode(..))
+        ));
+    }
+
+    #[test]
+    fn partial_path_keeps_extension() {
+        let path = Path::new("/cache/abc.png");
+        assert_eq!(Image::partial_path(path), Path::new("/cache/abc.part.png"));
+    }
+
+    #[test]
+    fn verify_cached_rejects_non_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let cached = Image::from_path(dir.path().join("cover.jpg"));
+        fs::write(&cached.path, "<html>503 Service Unavailable</html>").unwrap();
+        assert!(matches!(
+            cached.verify_cached(),
+            Err(ImageError::ImageDecode(..))
+        ));
+    }
 }
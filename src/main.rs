@@ -1,143 +1,1055 @@
-// use colored::Colorize;
+use clap::Parser;
 use std::{
+    collections::HashMap,
     error::Error,
-    io::{self, Write},
+    fmt, fs,
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    sync::Arc,
 };
 use strum::EnumMessage;
+use tokio::sync::watch;
+use tracing::Level;
+use tracing_subscriber::fmt::format::FmtSpan;
 
 pub mod api;
 pub mod auth;
+pub mod cli;
+pub mod client;
+pub mod color_space;
 pub mod config;
+pub mod export;
 pub mod image;
+pub mod render;
 
-use crate::api::Api;
+use crate::api::{
+    Album, Api, ApiError, Artist, Image as SpotifyImage, PlayHistoryItem, PlayingItem, Track,
+    distinct_albums,
+};
 use crate::auth::AuthToken;
-use crate::config::{Config, ItemType};
-use crate::image::Image;
+use crate::cli::{Cli, Command, ConfigCommand};
+use crate::client::build_http_client;
+use crate::color_space::{ANSI_RESET, hex_to_ansi, rgb_to_ansi};
+use crate::config::{Config, Format, ItemType};
+use crate::export::{ExportData, ExportFormat, write_export};
+use crate::image::{DownloadProgress, Image};
+use crate::render::{render_output, render_qr_code, render_text};
+
+const MAX_GENRES_PER_ARTIST: usize = 2;
+const MAX_TOP_GENRES: usize = 3;
+
+/// Process exit codes, so scripts can tell failure modes apart without parsing messages.
+#[derive(Debug, Clone, Copy)]
+pub enum ExitCode {
+    Success = 0,
+    Failure = 1,
+    NoData = 2,
+    AuthFailure = 3,
+    NetworkError = 4,
+    ConfigError = 5,
+    RenderError = 6,
+    // 128 + the signal number, as shells report it
+    Interrupted = 130,
+    Terminated = 143,
+}
+
+impl ExitCode {
+    fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+
+    fn from_error(err: &(dyn Error + 'static)) -> Self {
+        if let Some(err) = err.downcast_ref::<ExitError>() {
+            return err.code;
+        }
+        match err.downcast_ref::<ApiError>() {
+            Some(ApiError::NoListeningData(_)) => ExitCode::NoData,
+            Some(ApiError::ServiceUnavailable | ApiError::UnexpectedResponse(_)) => {
+                ExitCode::NetworkError
+            }
+            None if err.is::<reqwest::Error>() => ExitCode::NetworkError,
+            None => ExitCode::Failure,
+        }
+    }
+}
+
+// Tags an error with the exit code it should produce
+#[derive(Debug)]
+struct ExitError {
+    code: ExitCode,
+    source: Box<dyn Error>,
+}
+
+impl ExitError {
+    fn wrap(code: ExitCode) -> impl FnOnce(Box<dyn Error>) -> Box<dyn Error> {
+        move |source| Box::new(ExitError { code, source })
+    }
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Error for ExitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let config = Config::load();
-    let access_token = AuthToken::get_valid_token().await?;
-    let api = Api::new(access_token, config.time_range);
+async fn main() {
+    let cli = Cli::parse();
+    let code = match run(cli).await {
+        Ok(()) => ExitCode::Success,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from_error(err.as_ref())
+        }
+    };
+    code.exit();
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    // Status messages are logged at the info level, so they go to stderr and --quiet hides them
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(if cli.verbose {
+            Level::DEBUG
+        } else if cli.quiet {
+            Level::ERROR
+        } else {
+            Level::INFO
+        })
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(io::stderr);
+    if cli.verbose {
+        subscriber.init();
+    } else {
+        subscriber.without_time().with_target(false).init();
+    }
+
+    // When running from shell startup, get the token in the background while the config is loaded
+    // and the rest of the setup happens. Only the config file is read for this, since the full
+    // config isn't loaded yet. Config commands and --status never need a token.
+    let needs_token = !cli.status && !matches!(cli.command, Some(Command::Config { .. }));
+    let token_task = needs_token
+        .then(Config::load_file_lenient)
+        .filter(|file_config| file_config.startup_fetch)
+        .map(|mut file_config| {
+            file_config.quiet = cli.quiet;
+            let command = cli.command.clone();
+            tokio::spawn(async move {
+                let client = build_http_client(&file_config);
+                AuthToken::get_valid_token(&file_config, command.as_ref(), &client)
+                    .await
+                    .map_err(|err| err.to_string())
+            })
+        });
+
+    check_platform_dirs();
+    spawn_signal_handler();
+    // Config commands work on the file itself and don't need authorization
+    if let Some(Command::Config { action }) = &cli.command {
+        return run_config_command(action).map_err(ExitError::wrap(ExitCode::ConfigError));
+    }
+    let mut config = Config::load(cli.time_range, cli.format);
+    // Status messages are logged to stderr, so they stay out of structured output on stdout and
+    // only --quiet hides them
+    config.quiet = cli.quiet;
+
+    // Checked before any token is fetched, so it never refreshes or starts an authorization
+    if cli.status {
+        print_status();
+        return Ok(());
+    }
+
+    let client = Arc::new(build_http_client(&config));
+    let access_token = match token_task {
+        Some(token_task) => token_task
+            .await
+            .map_err(|err| err.into())
+            .and_then(|token| token.map_err(|err| err.into())),
+        None => AuthToken::get_valid_token(&config, cli.command.as_ref(), &client).await,
+    }
+    .map_err(ExitError::wrap(ExitCode::AuthFailure))?;
+    let api = Api::new(access_token, &config, Arc::clone(&client));
+
+    match cli.command {
+        Some(Command::Search { query }) => {
+            return run_search(&api, &client, &config, &query).await;
+        }
+        Some(Command::Export { format, output }) => {
+            return run_export(&api, &client, &config, format, output).await;
+        }
+        Some(Command::New) => {
+            return run_new_releases(&api, &client, &config).await;
+        }
+        Some(Command::Now) => {
+            return run_now_playing(&api, &client, &config).await;
+        }
+        Some(Command::Config { .. }) | None => {}
+    }
+
+    let (mut track_count, mut artist_count) = match config.format {
+        Format::Human => config.get_item_count(),
+        Format::Json | Format::Tsv => (config.list_count, config.list_count),
+    };
+    if cli.qr || cli.preview || cli.recommend {
+        track_count = track_count.max(1);
+    }
+    if cli.recommend {
+        artist_count = artist_count.max(1);
+    }
+    let (top_items, recently_played) = tokio::join!(
+        api.fetch_top_items_batch(track_count.into(), artist_count.into()),
+        api.fetch_recently_played(config.get_recently_played_count().into()),
+    );
+    let (tracks, artists) = top_items.map_err(exit_if_no_data)?;
+    // Recently played only fills one view, so losing it shouldn't take the rest of the output down
+    let recently_played = recently_played.unwrap_or_else(|err| {
+        tracing::warn!("Could not fetch recently played tracks: {}", err);
+        Vec::new()
+    });
+
+    if config.format != Format::Human {
+        return print_structured(config.format, &tracks, &artists);
+    }
+
+    let pending_image = prefetch_image(
+        &config,
+        &client,
+        &tracks,
+        &artists,
+        &recently_played,
+        ("Favorite track", "Favorite artist", "Top album"),
+    );
+
+    let recommendations = if cli.recommend {
+        let seed_tracks: Vec<&str> = tracks
+            .iter()
+            .filter_map(|t| t.original_id())
+            .take(1)
+            .collect();
+        let seed_artists: Vec<&str> = artists.iter().take(1).map(|a| a.id.as_str()).collect();
+        let recommendations = api
+            .fetch_recommendations(&seed_tracks, &seed_artists)
+            .await?;
+        Some(
+            recommendations
+                .into_iter()
+                .take(config.list_count.into())
+                .collect::<Vec<Track>>(),
+        )
+    } else {
+        None
+    };
+
+    let country = if cli.recommend || config.list_view == ItemType::Track {
+        user_country(&api).await
+    } else {
+        None
+    };
+
+    let (image, image_caption) = await_image(pending_image).await;
+    let colors = theme_colors(&config, image.as_ref());
+
+    let text_lines = if let Some(recommendations) = recommendations {
+        format_track_lines(
+            format!("{} Recommended Tracks:", icon(&config, "🎶")),
+            &recommendations,
+            &colors,
+            config.show_album_type,
+            country.as_deref(),
+        )
+    } else {
+        match config.list_view {
+            ItemType::Artist => format_artist_lines(
+                format!("{} Top {} Artists:", icon(&config, "🎤"), config.list_count),
+                &artists,
+                &colors,
+            ),
+            ItemType::Track => format_track_lines(
+                format!("{} Top {} Tracks:", icon(&config, "🎶"), config.list_count),
+                &tracks,
+                &colors,
+                config.show_album_type,
+                country.as_deref(),
+            ),
+            ItemType::Album => format_album_lines(
+                format!(
+                    "{} Albums of Your Top Tracks, by Best Track:",
+                    icon(&config, "💿")
+                ),
+                distinct_albums(&tracks),
+                &colors,
+            ),
+            ItemType::RecentlyPlayed => format_recently_played_lines(
+                format!("{} Recently Played:", icon(&config, "🎶")),
+                &recently_played,
+                &colors,
+            ),
+            ItemType::None => Vec::new(),
+        }
+    };
+
+    if let Some(image) = image
+        && let Some(image_caption) = image_caption
+    {
+        print_header(&config);
+        render_output(&config, &image, image_caption, text_lines)
+            .map_err(ExitError::wrap(ExitCode::RenderError))?;
+    } else if !text_lines.is_empty() {
+        print_header(&config);
+        render_text(&config, text_lines);
+    }
+
+    if cli.qr
+        && let Some(track) = tracks.first()
+    {
+        render_qr_code(&track.uri)?;
+    }
 
-    let (track_count, artist_count) = config.get_item_count();
-    let tracks = api.fetch_user_top_tracks(track_count as u32).await?;
-    let artists = api.fetch_user_top_artists(artist_count as u32).await?;
+    if cli.preview
+        && let Some(track) = tracks.first()
+    {
+        play_preview(&client, track).await?;
+    }
+
+    Ok(())
+}
+
+// Nothing is drawn, so this works without a terminal, e.g. in CI or a status bar script
+fn print_structured(
+    format: Format,
+    tracks: &[Track],
+    artists: &[Artist],
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Json => {
+            let json = serde_json::json!({ "tracks": tracks, "artists": artists });
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        Format::Tsv => {
+            // Tabs and newlines inside names would break up the columns
+            let field = |text: &str| text.replace(['\t', '\n'], " ");
+            for (i, track) in tracks.iter().enumerate() {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    i + 1,
+                    field(&track.name),
+                    field(&track.artist_names()),
+                    field(&track.album.name)
+                );
+            }
+            for (i, artist) in artists.iter().enumerate() {
+                println!(
+                    "{}\t{}\t{}",
+                    i + 1,
+                    field(&artist.name),
+                    field(&artist.genres.join(", "))
+                );
+            }
+        }
+        Format::Human => {}
+    }
+    Ok(())
+}
 
-    if tracks.is_empty() || artists.is_empty() {
+fn print_header(config: &Config) {
+    if !config.quiet {
         println!(
-            "You have no Spotify listening data from the most recent {}",
+            "Your Spotify stats from the most recent {}:",
             config.time_range.get_message().unwrap()
         );
-        std::process::exit(0);
     }
+}
 
-    let (image, image_caption) = match config.image_view {
-        ItemType::Track => {
-            if let Some(track) = tracks.first()
-                && let Ok(image) = Image::new(&track.album.images).await
-            {
-                let image_caption = format!("🎶 Favorite track: {}", track.format_track_display(),);
-                (Some(image), Some(image_caption))
-            } else {
-                (None, None)
-            }
+fn run_config_command(action: &ConfigCommand) -> Result<(), Box<dyn Error>> {
+    match action {
+        ConfigCommand::Set { key, value } => {
+            let mut config = Config::load_file()?;
+            let (old_value, new_value) = config.set_field(key, value)?;
+            config.save()?;
+            println!("{}: {} → {}", key, old_value, new_value);
         }
-        ItemType::Artist => {
-            if let Some(artist) = artists.first()
-                && let Ok(image) = Image::new(&artist.images).await
-            {
-                let image_caption = format!("🎤 Favorite artist: {}", artist.name);
-                (Some(image), Some(image_caption))
-            } else {
-                (None, None)
+        ConfigCommand::Get { key } => {
+            println!("{}", Config::load_file()?.get_field(key)?);
+        }
+        ConfigCommand::List => {
+            let config = Config::load_file_lenient();
+            println!("{}\n", config);
+            let fields = config.list_fields()?;
+            let key_width = fields
+                .iter()
+                .map(|(key, _, _)| key.len())
+                .max()
+                .unwrap_or(0);
+            let value_width = fields
+                .iter()
+                .map(|(_, value, _)| value.chars().count())
+                .max()
+                .unwrap_or(0);
+            for (key, value, valid_range) in fields {
+                println!(
+                    "{:key_width$}    {:value_width$}    {}",
+                    key, value, valid_range
+                );
             }
         }
+    }
+    Ok(())
+}
+
+async fn run_search(
+    api: &Api,
+    client: &reqwest::Client,
+    config: &Config,
+    query: &str,
+) -> Result<(), Box<dyn Error>> {
+    let results = api
+        .search(query, "track,artist", config.list_count.into())
+        .await?;
+    let tracks = results.tracks.map(|page| page.items).unwrap_or_default();
+    let artists = results.artists.map(|page| page.items).unwrap_or_default();
+
+    let pending_image = prefetch_image(
+        config,
+        client,
+        &tracks,
+        &artists,
+        &[],
+        ("Top track result", "Top artist result", "Top album result"),
+    );
+    let country = if config.list_view == ItemType::Track {
+        user_country(api).await
+    } else {
+        None
     };
 
+    let (image, image_caption) = await_image(pending_image).await;
+    let colors = theme_colors(config, image.as_ref());
+
     let text_lines = match config.list_view {
-        ItemType::Artist => {
-            let mut text_lines = vec![format!("🎤 Top {} Artists:", config.list_count)];
-            for (i, artist) in artists.iter().enumerate() {
-                text_lines.push(format!("  {}. {}", i + 1, artist.name));
-            }
-            text_lines
+        ItemType::Artist => format_artist_lines(
+            format!("{} Artists matching \"{}\":", icon(config, "🎤"), query),
+            &artists,
+            &colors,
+        ),
+        ItemType::Track => format_track_lines(
+            format!("{} Tracks matching \"{}\":", icon(config, "🎶"), query),
+            &tracks,
+            &colors,
+            config.show_album_type,
+            country.as_deref(),
+        ),
+        ItemType::Album => format_album_lines(
+            format!(
+                "{} Albums of tracks matching \"{}\":",
+                icon(config, "💿"),
+                query
+            ),
+            distinct_albums(&tracks),
+            &colors,
+        ),
+        // Search results have no play history
+        ItemType::RecentlyPlayed | ItemType::None => Vec::new(),
+    };
+
+    if let Some(image) = image
+        && let Some(image_caption) = image_caption
+    {
+        if !config.quiet {
+            println!("Spotify search results for \"{}\":", query);
         }
-        ItemType::Track => {
-            let mut text_lines = vec![format!("🎶 Top {} Tracks:", config.list_count)];
-            for (i, track) in tracks.iter().enumerate() {
-                text_lines.push(format!("  {}. {}", i + 1, track.format_track_display(),));
-            }
-            text_lines
+        render_output(config, &image, image_caption, text_lines)
+            .map_err(ExitError::wrap(ExitCode::RenderError))?;
+    } else if text_lines.len() > 1 {
+        if !config.quiet {
+            println!("Spotify search results for \"{}\":", query);
         }
+        render_text(config, text_lines);
+    } else {
+        println!("No results found for \"{}\"", query);
+    }
+
+    Ok(())
+}
+
+async fn run_new_releases(
+    api: &Api,
+    client: &reqwest::Client,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let albums = api.fetch_new_releases(config.list_count.into()).await?;
+
+    let pending_image = albums.first().map(|album| {
+        let image_caption = format!(
+            "{} Newest release: {} - {}",
+            icon(config, "💿"),
+            album.name,
+            album.artist_names()
+        );
+        (
+            prefetch_with_progress(&album.images, config, client),
+            image_caption,
+        )
+    });
+    let (image, image_caption) = await_image(pending_image).await;
+    let colors = theme_colors(config, image.as_ref());
+
+    let text_lines = format_album_lines(
+        format!("{} New Releases:", icon(config, "💿")),
+        &albums,
+        &colors,
+    );
+
+    if let Some(image) = image
+        && let Some(image_caption) = image_caption
+    {
+        if !config.quiet {
+            println!("New releases on Spotify:");
+        }
+        render_output(config, &image, image_caption, text_lines)
+            .map_err(ExitError::wrap(ExitCode::RenderError))?;
+    } else if text_lines.len() > 1 {
+        if !config.quiet {
+            println!("New releases on Spotify:");
+        }
+        render_text(config, text_lines);
+    } else {
+        println!("No new releases found");
+    }
+
+    Ok(())
+}
+
+async fn run_now_playing(
+    api: &Api,
+    client: &reqwest::Client,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let Some((item, is_playing, progress_ms)) = api
+        .fetch_currently_playing()
+        .await?
+        .and_then(|context| Some((context.item?, context.is_playing, context.progress_ms)))
+    else {
+        println!("Nothing currently playing");
+        return Ok(());
     };
 
+    let status = if is_playing { "Now playing" } else { "Paused" };
+    let (images, emoji, title, subtitle) = match &item {
+        PlayingItem::Track(track) => (
+            &track.album.images,
+            "🎶",
+            track.name.as_str(),
+            format!("{} ({})", track.artist_names(), track.album.name),
+        ),
+        PlayingItem::Episode(episode) => (
+            &episode.images,
+            "🎙️",
+            episode.name.as_str(),
+            episode.show.name.clone(),
+        ),
+    };
+    let image_caption = format!("{} {}: {}", icon(config, emoji), status, title);
+    let pending_image = Some((
+        prefetch_with_progress(images, config, client),
+        image_caption,
+    ));
+    let (image, image_caption) = await_image(pending_image).await;
+    let colors = theme_colors(config, image.as_ref());
+
+    let text_lines = vec![
+        format!("{} {}:", icon(config, emoji), status),
+        format!("  {}", paint(title, colors.track_names.as_deref())),
+        format!("  {}", paint(&subtitle, colors.artist_names.as_deref())),
+        format!(
+            "  {} {} / {}",
+            progress_bar(progress_ms, item.duration_ms()),
+            format_duration(progress_ms),
+            format_duration(item.duration_ms())
+        ),
+    ];
+
     if let Some(image) = image
         && let Some(image_caption) = image_caption
     {
-        render_output(&config, &image, image_caption, text_lines)?;
+        render_output(config, &image, image_caption, text_lines)
+            .map_err(ExitError::wrap(ExitCode::RenderError))?;
+    } else {
+        render_text(config, text_lines);
     }
 
-    std::process::exit(0);
+    Ok(())
+}
+
+fn progress_bar(progress_ms: u64, duration_ms: u64) -> String {
+    const BAR_WIDTH: u64 = 20;
+    let position = if duration_ms == 0 {
+        0
+    } else {
+        (progress_ms * BAR_WIDTH / duration_ms).min(BAR_WIDTH - 1)
+    };
+    format!(
+        "{}●{}",
+        "─".repeat(position as usize),
+        "─".repeat((BAR_WIDTH - 1 - position) as usize)
+    )
+}
+
+// As minutes and seconds, like 3:07
+fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
 }
 
-fn render_output(
+async fn run_export(
+    api: &Api,
+    client: &reqwest::Client,
     config: &Config,
-    image: &Image,
-    image_caption: String,
-    text_lines: Vec<String>,
+    format: ExportFormat,
+    output: Option<PathBuf>,
 ) -> Result<(), Box<dyn Error>> {
-    println!(
-        "Your Spotify stats from the most recent {}:",
-        config.time_range.get_message().unwrap()
-    );
+    let (tracks, artists) = api
+        .fetch_top_items_batch(config.list_count.into(), config.list_count.into())
+        .await?;
+
+    let image = match (format, tracks.first()) {
+        (ExportFormat::Html, Some(track)) => {
+            // The page shows the image larger than it would be in the terminal
+            Image::new(&track.album.images, None, config, client)
+                .await
+                .ok()
+        }
+        _ => None,
+    };
+
+    let data = ExportData {
+        time_range: config.time_range,
+        tracks: &tracks,
+        artists: &artists,
+        image: image.as_ref(),
+    };
+    write_export(format.exporter().as_ref(), &data, output)
+}
+
+fn prefetch_image(
+    config: &Config,
+    client: &reqwest::Client,
+    tracks: &[Track],
+    artists: &[Artist],
+    recently_played: &[PlayHistoryItem],
+    (track_label, artist_label, album_label): (&str, &str, &str),
+) -> Option<(
+    impl Future<Output = Result<Image, Box<dyn Error>>> + use<>,
+    String,
+)> {
+    let (images, image_caption) = match config.image_view {
+        ItemType::Track => {
+            let track = tracks.first()?;
+            let image_caption = format!(
+                "{} {}: {}",
+                icon(config, "🎶"),
+                track_label,
+                track.format_track_display()
+            );
+            (&track.album.images, image_caption)
+        }
+        ItemType::Artist => {
+            let artist = artists.first()?;
+            let image_caption = format!(
+                "{} {}: {}",
+                icon(config, "🎤"),
+                artist_label,
+                artist.format_artist_display(MAX_GENRES_PER_ARTIST)
+            );
+            (&artist.images, image_caption)
+        }
+        ItemType::Album => {
+            let album = &tracks.first()?.album;
+            let mut image_caption =
+                format!("{} {}: {}", icon(config, "💿"), album_label, album.name);
+            match album.release_year() {
+                Some(year) => {
+                    image_caption.push_str(&format!(" ({}, {} tracks)", year, album.total_tracks))
+                }
+                None => image_caption.push_str(&format!(" ({} tracks)", album.total_tracks)),
+            }
+            (&album.images, image_caption)
+        }
+        ItemType::RecentlyPlayed => {
+            let item = recently_played.first()?;
+            let image_caption = format!(
+                "{} Last played: {} ({})",
+                icon(config, "🎶"),
+                item.track.format_track_display(),
+                format_played_at(&item.played_at)
+            );
+            (&item.track.album.images, image_caption)
+        }
+        ItemType::None => return None,
+    };
+    Some((
+        prefetch_with_progress(images, config, client),
+        image_caption,
+    ))
+}
+
+// Show a progress bar on stderr while the image downloads, cleared before the output is drawn
+fn prefetch_with_progress(
+    images: &[SpotifyImage],
+    config: &Config,
+    client: &reqwest::Client,
+) -> impl Future<Output = Result<Image, Box<dyn Error>>> + use<> {
+    let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
+    let progress_task = (!config.quiet).then(|| tokio::spawn(show_download_progress(progress_rx)));
+    let image = Image::prefetch(images, config, client, progress_tx);
+    async move {
+        let image = image.await;
+        if let Some(progress_task) = progress_task {
+            progress_task.await.ok();
+        }
+        image
+    }
+}
+
+async fn show_download_progress(mut progress_rx: watch::Receiver<DownloadProgress>) {
+    const BAR_WIDTH: u64 = 20;
+    if !io::stderr().is_terminal() {
+        return;
+    }
 
-    let image_term_height = image.get_terminal_height(config.image_width.into())?;
-    let text_height = text_lines.len() as u32;
-    let total_height = (image_term_height + 1).max(text_height);
+    let mut shown = false;
+    while progress_rx.changed().await.is_ok() {
+        let progress = *progress_rx.borrow_and_update();
+        match progress.total_bytes {
+            Some(total_bytes) if total_bytes > 0 => {
+                let filled = (progress.downloaded_bytes * BAR_WIDTH / total_bytes).min(BAR_WIDTH);
+                eprint!(
+                    "\rDownloading image [{}{}] {}%",
+                    "#".repeat(filled as usize),
+                    " ".repeat((BAR_WIDTH - filled) as usize),
+                    (progress.downloaded_bytes * 100 / total_bytes).min(100)
+                );
+            }
+            _ => eprint!(
+                "\rDownloading image... {} KB",
+                progress.downloaded_bytes / 1024
+            ),
+        }
+        shown = true;
+    }
+    if shown {
+        eprint!("\r\x1b[2K");
+    }
+}
 
-    // Reserve vertical space by printing enough newlines
-    for _ in 0..total_height {
-        println!();
+async fn await_image(
+    pending_image: Option<(impl Future<Output = Result<Image, Box<dyn Error>>>, String)>,
+) -> (Option<Image>, Option<String>) {
+    match pending_image {
+        Some((image, image_caption)) => match image.await {
+            Ok(image) => (Some(image), Some(image_caption)),
+            Err(_) => (None, None),
+        },
+        None => (None, None),
     }
-    // Move cursor back up to where we want to start drawing
-    print!("\x1b[{}A", total_height);
-    io::stdout().flush()?;
+}
 
-    let conf = viuer::Config {
-        // width: Some(config.image_width.into()),
-        height: Some(image_term_height),
-        absolute_offset: false,
-        restore_cursor: false,
-        x: config.offset_x,
-        y: config.offset_y as i16,
-        ..Default::default()
+// Text stand-ins for fonts or terminals that can't display the emoji
+fn icon(config: &Config, emoji: &'static str) -> &'static str {
+    if config.use_emoji {
+        return emoji;
+    }
+    match emoji {
+        "🎶" => "[Track]",
+        "🎤" => "[Artist]",
+        "💿" => "[Album]",
+        "🎙️" => "[Episode]",
+        _ => "",
+    }
+}
+
+// The country is only used to flag unavailable tracks, so failing to fetch it isn't fatal
+async fn user_country(api: &Api) -> Option<String> {
+    api.fetch_user_profile()
+        .await
+        .ok()
+        .and_then(|profile| profile.country)
+}
+
+// Colors for each part of a list line, as ANSI escape sequences
+struct Palette {
+    track_names: Option<String>,
+    artist_names: Option<String>,
+    album_names: Option<String>,
+}
+
+// Configured hex colors take precedence over the ones picked from the image
+fn theme_colors(config: &Config, image: Option<&Image>) -> Palette {
+    let dominant = match image {
+        Some(image) if config.dynamic_colors => image.to_dominant_colors(2).unwrap_or_default(),
+        _ => Vec::new(),
     };
+    let dominant_color = |i: usize| dominant.get(i).copied().map(rgb_to_ansi);
+    let configured = |hex: &Option<String>| hex.as_deref().and_then(hex_to_ansi);
+    Palette {
+        track_names: configured(&config.color_track_names).or_else(|| dominant_color(0)),
+        artist_names: configured(&config.color_artist_names).or_else(|| dominant_color(0)),
+        album_names: dominant_color(1),
+    }
+}
 
-    // Print the image
-    viuer::print_from_file(&image.path, &conf)?;
-    println!("{}", image_caption);
+fn paint(text: &str, color: Option<&str>) -> String {
+    match color {
+        Some(color) if colored::control::SHOULD_COLORIZE.should_colorize() => {
+            format!("{}{}{}", color, text, ANSI_RESET)
+        }
+        _ => text.to_string(),
+    }
+}
 
-    // Move cursor back to top of image
-    print!("\x1b[{}A", image_term_height - 1);
-    io::stdout().flush()?;
+fn format_artist_lines(header: String, artists: &[Artist], colors: &Palette) -> Vec<String> {
+    let mut text_lines = vec![header];
+    for (i, artist) in artists.iter().enumerate() {
+        let mut line = format!(
+            "  {}. {}",
+            i + 1,
+            paint(&artist.name, colors.artist_names.as_deref())
+        );
+        let genres = artist.top_genres(MAX_GENRES_PER_ARTIST);
+        if !genres.is_empty() {
+            line.push_str(&format!(" ({})", genres.join(", ")));
+        }
+        text_lines.push(line);
+    }
+    let top_genres = top_genres_overall(artists, MAX_TOP_GENRES);
+    if !top_genres.is_empty() {
+        text_lines.push(format!("Top genres overall: {}", top_genres.join(", ")));
+    }
+    text_lines
+}
+
+// The genres shared by the most artists, ties going to the genre of the higher ranked artist
+fn top_genres_overall(artists: &[Artist], max_genres: usize) -> Vec<&str> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut genres = Vec::new();
+    for genre in artists.iter().flat_map(|artist| &artist.genres) {
+        let count = counts.entry(genre.as_str()).or_insert(0);
+        if *count == 0 {
+            genres.push(genre.as_str());
+        }
+        *count += 1;
+    }
+    genres.sort_by_key(|genre| std::cmp::Reverse(counts[genre]));
+    genres.truncate(max_genres);
+    genres
+}
+
+fn format_track_lines(
+    header: String,
+    tracks: &[Track],
+    colors: &Palette,
+    show_album_type: bool,
+    country: Option<&str>,
+) -> Vec<String> {
+    let mut text_lines = vec![header];
+    for (i, track) in tracks.iter().enumerate() {
+        let mut line = format!(
+            "  {}. {} - {} ({})",
+            i + 1,
+            paint(&track.name, colors.track_names.as_deref()),
+            paint(&track.artist_names(), colors.artist_names.as_deref()),
+            paint(&track.album.name, colors.album_names.as_deref())
+        );
+        if show_album_type && let Some(label) = track.album.type_label() {
+            line.push_str(&format!(" ({})", label));
+        }
+        if let Some(country) = country
+            && !track.is_available_in(country)
+        {
+            line.push_str(" ⚠ (unavailable in your region)");
+        }
+        text_lines.push(line);
+    }
+    text_lines
+}
 
-    // Move cursor right to position after image
-    let text_column = config.image_width + config.offset_x + config.gap;
+fn format_album_lines<'a>(
+    header: String,
+    albums: impl IntoIterator<Item = &'a Album>,
+    colors: &Palette,
+) -> Vec<String> {
+    let mut text_lines = vec![header];
+    for (i, album) in albums.into_iter().enumerate() {
+        text_lines.push(format!(
+            "  {}. {} - {}",
+            i + 1,
+            paint(&album.name, colors.track_names.as_deref()),
+            paint(&album.artist_names(), colors.artist_names.as_deref())
+        ));
+    }
+    text_lines
+}
 
-    for line in text_lines.iter() {
-        print!("\x1b[{}C{}", text_column, line); // Move right and print
-        print!("\x1b[1E"); // Move to beginning of next line
-        io::stdout().flush()?;
+fn format_recently_played_lines(
+    header: String,
+    items: &[PlayHistoryItem],
+    colors: &Palette,
+) -> Vec<String> {
+    let mut text_lines = vec![header];
+    for (i, item) in items.iter().enumerate() {
+        text_lines.push(format!(
+            "  {}. {} - {} ({})",
+            i + 1,
+            paint(&item.track.name, colors.track_names.as_deref()),
+            paint(&item.track.artist_names(), colors.artist_names.as_deref()),
+            format_played_at(&item.played_at)
+        ));
     }
+    text_lines
+}
 
-    // Move cursor below the image
-    let lines_printed = text_lines.len() as u32;
-    print!("\x1b[{}A", lines_printed);
-    io::stdout().flush()?;
-    print!("\x1b[{}B", total_height);
-    io::stdout().flush()?;
+// Relative to now, like "3 hours ago". Timestamps that can't be parsed are shown as they are.
+fn format_played_at(played_at: &str) -> String {
+    let Ok(played_at_time) = chrono::DateTime::parse_from_rfc3339(played_at) else {
+        return played_at.to_string();
+    };
+    let elapsed = chrono::Utc::now().signed_duration_since(played_at_time);
+    let (count, unit) = if elapsed.num_days() > 0 {
+        (elapsed.num_days(), "day")
+    } else if elapsed.num_hours() > 0 {
+        (elapsed.num_hours(), "hour")
+    } else if elapsed.num_minutes() > 0 {
+        (elapsed.num_minutes(), "minute")
+    } else {
+        return "just now".to_string();
+    };
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+// Having no listening data is not an error, so report it without the "Error:" prefix. Outages
+// are reported the same way, since there is nothing more useful to add.
+fn exit_if_no_data(err: Box<dyn Error>) -> Box<dyn Error> {
+    match err.downcast_ref::<ApiError>() {
+        Some(ApiError::NoListeningData(_)) => {
+            println!("{}", err);
+            ExitCode::NoData.exit();
+        }
+        Some(ApiError::ServiceUnavailable) => {
+            eprintln!("{}", err);
+            ExitCode::NetworkError.exit();
+        }
+        Some(ApiError::UnexpectedResponse(_)) | None => err,
+    }
+}
+
+// Output may be cut off halfway through drawing, so put the terminal back in order before exiting
+fn spawn_signal_handler() {
+    tokio::spawn(async {
+        let code = wait_for_signal().await;
+        restore_terminal();
+        code.exit();
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> ExitCode {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            _ = terminate.recv() => ExitCode::Terminated,
+            _ = wait_for_ctrl_c() => ExitCode::Interrupted,
+        },
+        Err(_) => {
+            wait_for_ctrl_c().await;
+            ExitCode::Interrupted
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> ExitCode {
+    wait_for_ctrl_c().await;
+    ExitCode::Interrupted
+}
+
+// Never resolves if the handler can't be installed, leaving the default behavior in place
+async fn wait_for_ctrl_c() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+fn restore_terminal() {
+    // Reset colors, show the cursor again and move to a fresh line
+    print!("\x1b[0m\x1b[?25h\n");
+    io::stdout().flush().ok();
+}
+
+// Fail with a hint instead of a panic deep inside path handling when the platform has no such
+// directories, e.g. when running without a home directory
+fn check_platform_dirs() {
+    let checks = [
+        (dirs::cache_dir(), "cache", "XDG_CACHE_HOME"),
+        (dirs::config_dir(), "config", "XDG_CONFIG_HOME"),
+        (dirs::data_dir(), "data", "XDG_DATA_HOME"),
+    ];
+    for (dir, name, env_var) in checks {
+        if dir.is_none() {
+            eprintln!(
+                "Cannot determine platform {} directory. Try setting ${}.",
+                name, env_var
+            );
+            ExitCode::ConfigError.exit();
+        }
+    }
+}
+
+fn print_status() {
+    match Config::get_config_path() {
+        Ok(path) => println!("Config file: {}", path.display()),
+        Err(err) => println!("Config file: unavailable ({})", err),
+    }
+    match AuthToken::load() {
+        Ok(token) => {
+            match token.token_age() {
+                Some(age) => println!("Token obtained {} minutes ago", age.as_secs() / 60),
+                None => println!("Token found, but its age is unknown"),
+            }
+            if let Some(scope) = token.scope() {
+                println!("Granted scopes: {}", scope);
+            }
+        }
+        Err(_) => println!("Not authorized yet"),
+    }
+}
+
+async fn play_preview(client: &reqwest::Client, track: &Track) -> Result<(), Box<dyn Error>> {
+    let Some(preview_url) = &track.preview_url else {
+        println!("(No preview available)");
+        return Ok(());
+    };
+
+    let response = client.get(preview_url).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to download preview: {}", response.status()).into());
+    }
+    let path = std::env::temp_dir().join(format!(
+        "spotifyfetch-preview-{}.mp3",
+        track.id.as_deref().unwrap_or("local")
+    ));
+    fs::write(&path, response.bytes().await?)?;
 
+    println!("Playing preview of {}", track.format_track_display());
+    open::that(&path)?;
     Ok(())
 }
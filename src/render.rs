@@ -0,0 +1,461 @@
+use colored::Colorize;
+use qrcode::{QrCode, render::unicode::Dense1x2};
+use std::{
+    error::Error,
+    io::{self, Write},
+};
+use unicode_width::UnicodeWidthChar;
+
+use crate::config::{Alignment, CaptionPosition, Config, ImagePosition};
+use crate::image::{Image, Rgb};
+
+/// Positions of everything drawn by `render_output`, in rows and columns relative to the top
+/// left corner of the reserved area.
+pub struct Layout {
+    pub image_start_row: u32,
+    pub image_start_col: u16,
+    pub text_start_row: u32,
+    pub text_start_col: u16,
+    pub image_height_rows: u32,
+    pub image_width_cols: u16,
+    pub border_width: u16,
+    pub caption_row: Option<u32>,
+    pub total_height_rows: u32,
+}
+
+impl Layout {
+    // Rows taken up by the image and its caption when it is drawn next to the image
+    fn image_block_rows(&self) -> u32 {
+        self.image_height_rows + u32::from(self.caption_row.is_some())
+    }
+
+    // Size of the image itself, inside the border if there is one
+    fn inner_image_cols(&self) -> u16 {
+        self.image_width_cols.saturating_sub(2 * self.border_width)
+    }
+
+    fn inner_image_rows(&self) -> u32 {
+        self.image_height_rows
+            .saturating_sub(2 * u32::from(self.border_width))
+    }
+}
+
+/// `image_height` is the height of the image alone; the border, if enabled, is added around it
+/// within `image_width` columns.
+pub fn compute_layout(
+    config: &Config,
+    image_height: u32,
+    text_lines: &[String],
+    term_cols: u16,
+) -> Layout {
+    let text_width = text_lines
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0) as u16;
+    let (image_start_col, text_start_col) = match config.image_position {
+        ImagePosition::Left => (
+            config.offset_x,
+            config.image_width + config.offset_x + config.gap,
+        ),
+        ImagePosition::Right => {
+            // Keep the image on screen even when the list is wider than the terminal
+            let image_column = (config.offset_x + text_width + config.gap)
+                .min(term_cols.saturating_sub(config.image_width));
+            (image_column, config.offset_x)
+        }
+    };
+
+    let border_width = u16::from(config.image_border);
+    let image_height = image_height + 2 * u32::from(border_width);
+    let (image_start_row, caption_row) = match config.caption_position {
+        CaptionPosition::AboveImage => (1, Some(0)),
+        CaptionPosition::BelowImage => (0, Some(image_height)),
+        CaptionPosition::BelowList | CaptionPosition::None => (0, None),
+    };
+    let image_block_rows = image_height + u32::from(caption_row.is_some());
+
+    Layout {
+        image_start_row,
+        image_start_col,
+        text_start_row: 0,
+        text_start_col,
+        image_height_rows: image_height,
+        image_width_cols: config.image_width,
+        border_width,
+        caption_row,
+        total_height_rows: image_block_rows.max(text_lines.len() as u32),
+    }
+}
+
+// Count terminal columns of printable characters, skipping ANSI escape sequences
+fn visible_width(line: &str) -> usize {
+    let mut width = 0;
+    let mut in_escape = false;
+    for c in line.chars() {
+        if in_escape {
+            in_escape = !c.is_ascii_alphabetic();
+        } else if c == '\x1b' {
+            in_escape = true;
+        } else {
+            width += c.width().unwrap_or(0);
+        }
+    }
+    width
+}
+
+pub fn render_output(
+    config: &Config,
+    image: &Image,
+    image_caption: String,
+    text_lines: Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let caption = match config.caption_position {
+        CaptionPosition::BelowList => Some(image_caption.clone()),
+        _ => None,
+    };
+    let text_lines = prepare_text_lines(config, text_lines, max_text_lines(config), caption);
+    let image_width = if config.image_border {
+        config.image_width.saturating_sub(2)
+    } else {
+        config.image_width
+    };
+    let image_height = image.get_terminal_height(image_width.into())?;
+    let (term_cols, _) = viuer::terminal_size();
+    let layout = compute_layout(config, image_height, &text_lines, term_cols);
+
+    // Reserve vertical space by printing enough newlines
+    for _ in 0..layout.total_height_rows {
+        println!();
+    }
+    // Move cursor back up to where we want to start drawing
+    print!("\x1b[{}A", layout.total_height_rows);
+    io::stdout().flush()?;
+
+    let conf = viuer::Config {
+        width: Some(layout.inner_image_cols().into()),
+        height: Some(layout.inner_image_rows()),
+        absolute_offset: false,
+        restore_cursor: false,
+        x: layout.image_start_col + layout.border_width,
+        y: if config.image_border {
+            0
+        } else {
+            config.offset_y as i16
+        },
+        ..Default::default()
+    };
+
+    let caption_indent = match config.image_position {
+        ImagePosition::Left => String::new(),
+        ImagePosition::Right => cursor_right(layout.image_start_col),
+    };
+
+    // Print the image with its caption
+    if layout.caption_row == Some(0) {
+        println!("{}{}", caption_indent, image_caption);
+    }
+    if config.image_border {
+        print_image_with_border(config, &layout, image, &conf)?;
+    } else {
+        print_image(config, image, &conf)?;
+    }
+    if layout.caption_row == Some(layout.image_start_row + layout.image_height_rows) {
+        println!("{}{}", caption_indent, image_caption);
+    }
+
+    if text_lines.is_empty() {
+        return Ok(());
+    }
+
+    // Move cursor back to the first text row
+    print!(
+        "{}",
+        cursor_up((layout.image_block_rows() + layout.text_start_row).saturating_sub(2))
+    );
+    io::stdout().flush()?;
+
+    for line in text_lines.iter() {
+        print!("{}{}", cursor_right(layout.text_start_col), line); // Move right and print
+        print!("\x1b[1E"); // Move to beginning of next line
+        io::stdout().flush()?;
+    }
+
+    // Move cursor below the image
+    let lines_printed = text_lines.len() as u32;
+    print!("\x1b[{}A", lines_printed);
+    io::stdout().flush()?;
+    print!("\x1b[{}B", layout.total_height_rows);
+    io::stdout().flush()?;
+
+    Ok(())
+}
+
+// Fall back to character art when viuer can't draw the image
+fn print_image(config: &Config, image: &Image, conf: &viuer::Config) -> Result<(), Box<dyn Error>> {
+    let Err(err) = viuer::print_from_file(&image.path, conf) else {
+        return Ok(());
+    };
+    tracing::warn!(
+        "Could not display image, drawing it as text instead: {}",
+        err
+    );
+
+    let width = conf.width.ok_or("Image width is not set")?;
+    let art = image.to_unicode_art(width, config.ascii_palette.palette())?;
+    for _ in 0..conf.y {
+        println!();
+    }
+    for line in art.lines() {
+        println!("{}{}", cursor_right(conf.x), line);
+    }
+    Ok(())
+}
+
+// Draw the top border, the image, then the sides and bottom once the image height is known
+fn print_image_with_border(
+    config: &Config,
+    layout: &Layout,
+    image: &Image,
+    conf: &viuer::Config,
+) -> Result<(), Box<dyn Error>> {
+    let indent = cursor_right(layout.image_start_col);
+    let inner_cols = layout.inner_image_cols();
+    let horizontal = "─".repeat(inner_cols.into());
+    let color = config.image_border_color.as_ref();
+
+    for _ in 0..config.offset_y {
+        println!();
+    }
+    println!(
+        "{}{}",
+        indent,
+        paint_border(&format!("┌{}┐", horizontal), color)
+    );
+    print_image(config, image, conf)?;
+
+    print!("{}", cursor_up(layout.inner_image_rows()));
+    for _ in 0..layout.inner_image_rows() {
+        print!(
+            "{}{}{}{}",
+            indent,
+            paint_border("│", color),
+            cursor_right(inner_cols),
+            paint_border("│", color)
+        );
+        print!("\x1b[1E");
+    }
+    println!(
+        "{}{}",
+        indent,
+        paint_border(&format!("└{}┘", horizontal), color)
+    );
+    io::stdout().flush()?;
+    Ok(())
+}
+
+fn paint_border(text: &str, color: Option<&Rgb>) -> String {
+    match color {
+        Some(&(r, g, b)) => text.truecolor(r, g, b).to_string(),
+        None => text.to_string(),
+    }
+}
+
+// A zero-column move is treated as one column by most terminals, so skip it entirely
+fn cursor_right(columns: u16) -> String {
+    if columns == 0 {
+        String::new()
+    } else {
+        format!("\x1b[{}C", columns)
+    }
+}
+
+fn cursor_up(rows: u32) -> String {
+    if rows == 0 {
+        String::new()
+    } else {
+        format!("\x1b[{}A", rows)
+    }
+}
+
+pub fn render_text(config: &Config, text_lines: Vec<String>) {
+    print!(
+        "{}",
+        render_to_string(config, text_lines, max_text_lines(config))
+    );
+}
+
+/// Renders the text-only output into a string instead of writing it to stdout, showing at most
+/// `max_text_lines` lines (0 for no limit). Output with an image can't be captured this way,
+/// since viuer draws straight to the terminal.
+pub fn render_to_string(config: &Config, text_lines: Vec<String>, max_text_lines: usize) -> String {
+    let indent = " ".repeat(config.offset_x.into());
+    let mut output = String::new();
+    for line in prepare_text_lines(config, text_lines, max_text_lines, None) {
+        output.push_str(&format!("{}{}\n", indent, line));
+    }
+    output
+}
+
+// Cap, border and align the list, with the caption below it when there is one
+fn prepare_text_lines(
+    config: &Config,
+    text_lines: Vec<String>,
+    max_text_lines: usize,
+    caption: Option<String>,
+) -> Vec<String> {
+    let mut text_lines = cap_text_lines(text_lines, max_text_lines);
+    if config.list_border {
+        text_lines = border_lines(&align_lines(&text_lines, config.list_alignment));
+    }
+    if let Some(caption) = caption {
+        text_lines.push(String::new());
+        text_lines.push(caption);
+    }
+    align_lines(&text_lines, config.list_alignment)
+}
+
+pub fn render_qr_code(uri: &str) -> Result<(), Box<dyn Error>> {
+    let code = QrCode::new(uri.as_bytes())?;
+    let (term_cols, _) = viuer::terminal_size();
+
+    // Drop the quiet zone if the full code would not fit in the terminal
+    for quiet_zone in [true, false] {
+        let qr = code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .quiet_zone(quiet_zone)
+            .build();
+        let qr_width = qr
+            .lines()
+            .map(|line| line.chars().count())
+            .max()
+            .unwrap_or(0);
+        if qr_width <= term_cols as usize {
+            println!("{}", qr);
+            return Ok(());
+        }
+    }
+
+    println!("(Terminal too narrow to display QR code for {})", uri);
+    Ok(())
+}
+
+// The configured limit, or else as many lines as fit in the terminal
+fn max_text_lines(config: &Config) -> usize {
+    // Leave room for the header and the shell prompt when the terminal height is known
+    config.max_text_lines.unwrap_or_else(|| {
+        crossterm::terminal::size()
+            .map(|(_, rows)| (rows as usize).saturating_sub(3))
+            .unwrap_or(usize::MAX)
+    })
+}
+
+fn cap_text_lines(mut text_lines: Vec<String>, max_text_lines: usize) -> Vec<String> {
+    if max_text_lines > 0 && text_lines.len() > max_text_lines {
+        let shown = max_text_lines - 1;
+        let hidden = text_lines.len() - shown;
+        text_lines.truncate(shown);
+        text_lines.push(format!("  ... ({} more)", hidden));
+    }
+    text_lines
+}
+
+fn align_lines(lines: &[String], alignment: Alignment) -> Vec<String> {
+    let max_len = lines
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| {
+            let padding = max_len - visible_width(line);
+            match alignment {
+                Alignment::Left => line.clone(),
+                Alignment::Center => format!("{}{}", " ".repeat(padding / 2), line),
+                Alignment::Right => format!("{}{}", " ".repeat(padding), line),
+            }
+        })
+        .collect()
+}
+
+// Box the lines in, padding each one to the widest so the right edge lines up
+fn border_lines(lines: &[String]) -> Vec<String> {
+    if lines.is_empty() {
+        return Vec::new();
+    }
+    let max_len = lines
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+
+    let horizontal = "─".repeat(max_len + 2);
+    let mut bordered = vec![format!("┌{}┐", horizontal)];
+    for line in lines {
+        let padding = " ".repeat(max_len - visible_width(line));
+        bordered.push(format!("│ {}{} │", line, padding));
+    }
+    bordered.push(format!("└{}┘", horizontal));
+    bordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artist_lines() -> Vec<String> {
+        let mut lines = vec!["Top 10 Artists:".to_string()];
+        lines.extend((1..=10).map(|i| format!("  {}. Artist Name", i)));
+        lines
+    }
+
+    #[test]
+    fn render_to_string_includes_header_and_items() {
+        let output = render_to_string(&Config::default(), artist_lines(), usize::MAX);
+        assert!(output.contains("Top 10 Artists:"));
+        assert!(output.contains("1. Artist Name"));
+        assert!(output.contains("10. Artist Name"));
+        assert_eq!(output.lines().count(), 11);
+    }
+
+    #[test]
+    fn render_to_string_caps_lines() {
+        let output = render_to_string(&Config::default(), artist_lines(), 5);
+        assert!(output.contains("3. Artist Name"));
+        assert!(!output.contains("4. Artist Name"));
+        assert!(output.contains("... (7 more)"));
+    }
+
+    #[test]
+    fn render_to_string_borders_list() {
+        let config = Config {
+            list_border: true,
+            ..Config::default()
+        };
+        let output = render_to_string(&config, artist_lines(), usize::MAX);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 13);
+        assert!(lines[0].trim_start().starts_with('┌'));
+        assert!(lines[12].trim_start().starts_with('└'));
+        assert!(
+            lines
+                .iter()
+                .all(|line| visible_width(line) == visible_width(lines[0]))
+        );
+    }
+
+    #[test]
+    fn visible_width_skips_ansi_escapes() {
+        assert_eq!(visible_width("\x1b[1;32mTop Tracks\x1b[0m"), 10);
+    }
+
+    #[test]
+    fn visible_width_counts_wide_characters() {
+        assert_eq!(visible_width("🎵 Now Playing"), 14);
+        assert_eq!(visible_width("東京"), 4);
+    }
+}